@@ -0,0 +1,7 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate rotor_stream;
+
+fuzz_target!(|data: &[u8]| {
+    rotor_stream::testing::check_expectations(data);
+});
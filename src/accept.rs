@@ -1,10 +1,17 @@
+use std::fmt;
 use std::any::Any;
+use std::usize;
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use rotor::{Machine, Response, EventSet, PollOpt, Evented};
-use rotor::{Scope, GenericScope, Void};
+use rotor::{Scope, GenericScope, Void, Notifier};
 use rotor::mio::{TryAccept};
 
-use {StreamSocket, Accept};
+use {StreamSocket, Accept, AcceptOptions, Drain, StreamOptions};
+use drain::{add_server, connection_opened, connection_closed};
+use metrics::report;
 
 
 /// Trait which must be implemented for a state machine to accept connection
@@ -17,9 +24,133 @@ pub trait Accepted: Machine {
     fn accepted(sock: Self::Socket, seed: <Self as Accepted>::Seed,
         scope: &mut Scope<Self::Context>)
         -> Response<Self, Void>;
+    /// The constructor which receives stream options of the acceptor
+    ///
+    /// This is what `Accept` calls. Default implementation ignores the
+    /// options and calls `accepted()`, state machines that wrap `Stream`
+    /// should pass the options down to it.
+    fn accepted_with_options(sock: Self::Socket,
+        seed: <Self as Accepted>::Seed, _options: &StreamOptions,
+        scope: &mut Scope<Self::Context>)
+        -> Response<Self, Void>
+    {
+        Self::accepted(sock, seed, scope)
+    }
 }
 
 
+/// State of the listening socket of `Accept`
+///
+/// It's created by the constructors of `Accept`, e.g. `Accept::new`.
+#[derive(Debug)]
+pub struct ServerState {
+    options: AcceptOptions,
+    // number of connections accepted in the current batch
+    accepted: usize,
+}
+
+/// Handles that account the accepted connection in the `Drain` and in the
+/// connection limit of the acceptor, if any
+#[derive(Debug)]
+pub struct ConnectionSlot {
+    drain: Option<Drain>,
+    limit: Option<Arc<ConnectionLimit>>,
+}
+
+#[derive(Debug)]
+pub struct ConnectionLimit {
+    max: usize,
+    current: AtomicUsize,
+    next_key: AtomicUsize,
+    // acceptors waiting for connections to be closed
+    servers: Mutex<HashMap<usize, Notifier>>,
+}
+
+impl ConnectionLimit {
+    fn add_server(&self, notifier: Notifier) -> usize {
+        let key = self.next_key.fetch_add(1, Ordering::SeqCst);
+        self.servers.lock().unwrap().insert(key, notifier);
+        key
+    }
+    fn remove_server(&self, key: usize) {
+        self.servers.lock().unwrap().remove(&key);
+    }
+}
+
+impl Default for AcceptOptions {
+    fn default() -> AcceptOptions {
+        AcceptOptions::new()
+    }
+}
+
+impl AcceptOptions {
+    /// Default options: no drain handle and no limits
+    pub fn new() -> AcceptOptions {
+        AcceptOptions {
+            drain: None,
+            batch_limit: usize::MAX,
+            limit: None,
+            limit_key: None,
+            stream: StreamOptions::new(),
+        }
+    }
+    /// Make acceptor stoppable by the `Drain` handle
+    ///
+    /// See `Drain` for more info
+    pub fn drain(mut self, drain: &Drain) -> AcceptOptions {
+        self.drain = Some(drain.clone());
+        self
+    }
+    /// Maximum number of connections accepted in a single batch
+    ///
+    /// By default acceptor accepts connections until there is nothing
+    /// left in the backlog of the listening socket. Under high rate of
+    /// incoming connections this may take a lot of time and starve
+    /// established connections on the same loop. When the limit is
+    /// reached the acceptor yields to the loop and continues accepting
+    /// on the next iteration.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is zero
+    pub fn batch_limit(mut self, limit: usize) -> AcceptOptions {
+        assert!(limit > 0);
+        self.batch_limit = limit;
+        self
+    }
+    /// Maximum number of simultaneous connections
+    ///
+    /// When the limit is reached acceptor stops accepting connections until
+    /// some of the connections are closed. The limit is shared between all
+    /// acceptors created with this options object (or it's clones), so you
+    /// may limit connections on multiple listening sockets at once. See
+    /// also `ListenerSet`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is zero
+    pub fn max_connections(mut self, max: usize) -> AcceptOptions {
+        assert!(max > 0);
+        self.limit = Some(Arc::new(ConnectionLimit {
+            max: max,
+            current: AtomicUsize::new(0),
+            next_key: AtomicUsize::new(0),
+            servers: Mutex::new(HashMap::new()),
+        }));
+        self
+    }
+    /// Options for the accepted streams
+    ///
+    /// These are passed to `Accepted::accepted_with_options` of the state
+    /// machine, so apply to `Stream` and `ProxyProtocolStream`.
+    pub fn stream_options(mut self, options: StreamOptions)
+        -> AcceptOptions
+    {
+        self.stream = options;
+        self
+    }
+}
+
 impl<M, A> Accept<M, A>
     where A: TryAccept<Output=M::Socket> + Evented + Any,
           M: Accepted,
@@ -27,73 +158,172 @@ impl<M, A> Accept<M, A>
     pub fn new<S: GenericScope>(sock: A,
         seed: <M as Accepted>::Seed, scope: &mut S)
         -> Response<Self, Void>
+    {
+        Accept::with_options(sock, seed, AcceptOptions::new(), scope)
+    }
+    /// Create an acceptor which may be shut down by the `Drain` handle
+    ///
+    /// See `Drain` for more info
+    pub fn with_drain<S: GenericScope>(sock: A,
+        seed: <M as Accepted>::Seed, drain: &Drain, scope: &mut S)
+        -> Response<Self, Void>
+    {
+        Accept::with_options(sock, seed, AcceptOptions::new().drain(drain),
+                             scope)
+    }
+    /// Create an acceptor with the specified options
+    ///
+    /// See `AcceptOptions` for more info
+    pub fn with_options<S: GenericScope>(sock: A,
+        seed: <M as Accepted>::Seed, mut options: AcceptOptions,
+        scope: &mut S)
+        -> Response<Self, Void>
     {
         match scope.register(&sock, EventSet::readable(), PollOpt::edge()) {
             Ok(()) => {}
             Err(e) => return Response::error(Box::new(e)),
         }
-        Response::ok(Accept::Server(sock, seed))
+        if let Some(ref drain) = options.drain {
+            add_server(drain, scope.notifier());
+        }
+        if let Some(ref limit) = options.limit {
+            options.limit_key = Some(limit.add_server(scope.notifier()));
+        }
+        let state = ServerState { options: options, accepted: 0 };
+        Response::ok(Accept::Server(sock, seed, state))
+    }
+}
+
+impl<M, A> fmt::Debug for Accept<M, A>
+    where A: TryAccept<Output=M::Socket> + Evented + Any,
+          M: Accepted,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Accept::Server(_, _, ref state) => {
+                write!(f, "Accept::Server(accepted: {})", state.accepted)
+            }
+            Accept::Connection(..) => write!(f, "Accept::Connection"),
+        }
     }
 }
 
+fn accept<M, A>(sock: A, seed: <M as Accepted>::Seed,
+    mut state: ServerState, scope: &mut Scope<M::Context>)
+    -> Response<Accept<M, A>, <Accept<M, A> as Machine>::Seed>
+    where A: TryAccept<Output=M::Socket> + Evented + Any,
+          M: Accepted,
+{
+    if state.accepted >= state.options.batch_limit {
+        // Yield to the loop, we continue accepting in `timeout` handler
+        let now = scope.now();
+        return Response::ok(Accept::Server(sock, seed, state))
+            .deadline(now);
+    }
+    let limit_reached = state.options.limit.as_ref()
+        .map(|x| x.current.load(Ordering::SeqCst) >= x.max)
+        .unwrap_or(false);
+    if limit_reached {
+        // We will be woken up when some connection is closed
+        state.accepted = 0;
+        return Response::ok(Accept::Server(sock, seed, state));
+    }
+    match sock.accept() {
+        Ok(Some(conn)) => {
+            report(|m| m.connection_accepted());
+            let mut stream = state.options.stream.clone();
+            if let Some(ref drain) = state.options.drain {
+                stream = stream.drain(drain);
+            }
+            let slot = ConnectionSlot {
+                drain: state.options.drain.clone(),
+                limit: state.options.limit.clone(),
+            };
+            let child = (conn, seed.clone(), stream, slot);
+            state.accepted += 1;
+            Response::spawn(Accept::Server(sock, seed, state), child)
+        }
+        Ok(None) =>  {
+            state.accepted = 0;
+            Response::ok(Accept::Server(sock, seed, state))
+        }
+        Err(e) => {
+            warn!("Error accepting connection: {}", e);
+            report(|m| m.accept_error(&e));
+            state.accepted = 0;
+            Response::ok(Accept::Server(sock, seed, state))
+        }
+    }
+}
+
+fn connection<M, A>(resp: Response<M, <M as Machine>::Seed>,
+    slot: ConnectionSlot, scope: &mut Scope<M::Context>)
+    -> Response<Accept<M, A>, <Accept<M, A> as Machine>::Seed>
+    where A: TryAccept<Output=M::Socket> + Evented + Any,
+          M: Accepted,
+{
+    if resp.is_stopped() {
+        if let Some(ref limit) = slot.limit {
+            let old = limit.current.fetch_sub(1, Ordering::SeqCst);
+            if old == limit.max {
+                for notifier in limit.servers.lock().unwrap().values() {
+                    notifier.wakeup().ok();
+                }
+            }
+        }
+        if let Some(ref drain) = slot.drain {
+            if connection_closed(drain) {
+                scope.shutdown_loop();
+            }
+        }
+    }
+    resp.map(|m| Accept::Connection(m, slot), |_| unreachable!())
+}
+
 impl<M, A> Machine for Accept<M, A>
     where A: TryAccept<Output=M::Socket> + Evented + Any,
           M: Accepted,
 {
     type Context = M::Context;
-    type Seed = (A::Output, <M as Accepted>::Seed);
-    fn create((sock, seed): Self::Seed, scope: &mut Scope<Self::Context>)
+    type Seed = (A::Output, <M as Accepted>::Seed, StreamOptions,
+                 ConnectionSlot);
+    fn create((sock, seed, options, slot): Self::Seed,
+        scope: &mut Scope<Self::Context>)
         -> Response<Self, Void>
     {
-        M::accepted(sock, seed, scope).wrap(Accept::Connection)
+        let resp = M::accepted_with_options(sock, seed, &options, scope);
+        if !resp.is_stopped() {
+            if let Some(ref limit) = slot.limit {
+                limit.current.fetch_add(1, Ordering::SeqCst);
+            }
+            if let Some(ref drain) = slot.drain {
+                connection_opened(drain);
+            }
+        }
+        resp.wrap(|m| Accept::Connection(m, slot))
     }
 
     fn ready(self, events: EventSet, scope: &mut Scope<Self::Context>)
         -> Response<Self, Self::Seed>
     {
         match self {
-            Accept::Server(a, s) => {
-                match a.accept() {
-                    Ok(Some(sock)) => {
-                        let seed = (sock, s.clone());
-                        Response::spawn(Accept::Server(a, s), seed)
-                    }
-                    Ok(None) =>  {
-                        Response::ok(Accept::Server(a, s))
-                    }
-                    Err(_) => {
-                        // TODO(tailhook) maybe log the error
-                        Response::ok(Accept::Server(a, s))
-                    }
-                }
+            Accept::Server(a, s, mut state) => {
+                state.accepted = 0;
+                accept(a, s, state, scope)
             }
-            Accept::Connection(m) => {
-                m.ready(events, scope)
-                    .map(Accept::Connection, |_| unreachable!())
+            Accept::Connection(m, slot) => {
+                let resp = m.ready(events, scope);
+                connection(resp, slot, scope)
             }
         }
     }
 
-    fn spawned(self, _scope: &mut Scope<Self::Context>)
+    fn spawned(self, scope: &mut Scope<Self::Context>)
         -> Response<Self, Self::Seed>
     {
         match self {
-            Accept::Server(a, s) => {
-                match a.accept() {
-                    Ok(Some(sock)) => {
-                        let seed = (sock, s.clone());
-                        Response::spawn(Accept::Server(a, s), seed)
-                    }
-                    Ok(None) =>  {
-                        Response::ok(Accept::Server(a, s))
-                    }
-                    Err(_) => {
-                        // TODO(tailhook) maybe log the error
-                        Response::ok(Accept::Server(a, s))
-                    }
-                }
-            }
-            Accept::Connection(_) => {
+            Accept::Server(a, s, state) => accept(a, s, state, scope),
+            Accept::Connection(..) => {
                 unreachable!();
             }
         }
@@ -103,9 +333,14 @@ impl<M, A> Machine for Accept<M, A>
         -> Response<Self, Self::Seed>
     {
         match self {
-            Accept::Server(..) => unreachable!(),
-            Accept::Connection(m) => {
-                m.timeout(scope).map(Accept::Connection, |_| unreachable!())
+            // Either the batch limit is reached or a spurious timeout
+            Accept::Server(a, s, mut state) => {
+                state.accepted = 0;
+                accept(a, s, state, scope)
+            }
+            Accept::Connection(m, slot) => {
+                let resp = m.timeout(scope);
+                connection(resp, slot, scope)
             }
         }
     }
@@ -114,10 +349,119 @@ impl<M, A> Machine for Accept<M, A>
         -> Response<Self, Self::Seed>
     {
         match self {
-            me @ Accept::Server(..) => Response::ok(me),
-            Accept::Connection(m) => {
-                m.wakeup(scope).map(Accept::Connection, |_| unreachable!())
+            Accept::Server(a, s, state) => {
+                let draining = state.options.drain.as_ref()
+                    .map(|d| d.is_draining()).unwrap_or(false);
+                if draining {
+                    scope.deregister(&a).ok();
+                    let o = state.options;
+                    if let (Some(limit), Some(key)) = (o.limit, o.limit_key) {
+                        limit.remove_server(key);
+                    }
+                    if o.drain.as_ref().unwrap().connections() == 0 {
+                        scope.shutdown_loop();
+                    }
+                    // Listening socket is closed when dropped
+                    Response::done()
+                } else {
+                    // This also restores deadline if the batch limit
+                    // has been reached
+                    accept(a, s, state, scope)
+                }
+            }
+            Accept::Connection(m, slot) => {
+                let resp = m.wakeup(scope);
+                connection(resp, slot, scope)
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpStream as StdStream;
+
+    use std::time::Duration;
+
+    use rotor::{Machine, EventSet, Time};
+    use rotor::mio::tcp::{TcpListener, TcpStream};
+    use testing::with_scope;
+    use testing::echo::Echo;
+    use {Accept, AcceptOptions, Stream, Drain};
+
+    type Server = Accept<Stream<Echo<TcpStream>>, TcpListener>;
+
+    #[test]
+    fn batch_limit() {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap())
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _clients = (0..3).map(|_| StdStream::connect(addr).unwrap())
+            .collect::<Vec<_>>();
+        with_scope(&mut (), Time::zero(), |scope| {
+            let options = AcceptOptions::new().batch_limit(2);
+            let server = Server::with_options(listener, (), options, scope)
+                .expect_machine();
+            let (server, _) = server.ready(EventSet::readable(), scope)
+                .expect_spawn();
+            let (server, _) = server.spawned(scope).expect_spawn();
+            // Yields although there is a pending connection
+            let server = server.spawned(scope).expect_machine();
+            // And continues on the next iteration of the loop
+            let (server, _) = server.timeout(scope).expect_spawn();
+            server.spawned(scope).expect_machine();
+        });
+    }
+
+    #[test]
+    fn max_connections() {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap())
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _clients = (0..2).map(|_| StdStream::connect(addr).unwrap())
+            .collect::<Vec<_>>();
+        let (server, conn) = with_scope(&mut (), Time::zero(), |scope| {
+            let options = AcceptOptions::new().max_connections(1);
+            let server = Server::with_options(listener, (), options, scope)
+                .expect_machine();
+            let (server, seed) = server.ready(EventSet::readable(), scope)
+                .expect_spawn();
+            let conn = Server::create(seed, scope).expect_machine();
+            // Paused although there is a pending connection
+            let server = server.spawned(scope).expect_machine();
+            let server = server.timeout(scope).expect_machine();
+            (server, conn)
+        });
+        // Echo closes the connection on timeout
+        let later = Time::zero() + Duration::new(2, 0);
+        with_scope(&mut (), later, |scope| {
+            conn.timeout(scope).expect_done();
+            // Woken up by the closed connection, accepts again
+            let (server, _) = server.wakeup(scope).expect_spawn();
+            server.spawned(scope).expect_machine();
+        });
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_connections() {
+        AcceptOptions::new().max_connections(0);
+    }
+
+    #[test]
+    fn remove_finished_server() {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap())
+            .unwrap();
+        let drain = Drain::new();
+        let options = AcceptOptions::new().drain(&drain).max_connections(1);
+        let limit = options.limit.clone().unwrap();
+        with_scope(&mut (), Time::zero(), |scope| {
+            let server = Server::with_options(listener, (), options, scope)
+                .expect_machine();
+            assert_eq!(limit.servers.lock().unwrap().len(), 1);
+            drain.start();
+            server.wakeup(scope).expect_done();
+        });
+        assert_eq!(limit.servers.lock().unwrap().len(), 0);
+    }
+}
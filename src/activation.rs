@@ -0,0 +1,362 @@
+use std::io;
+use std::env;
+use std::mem;
+use std::net;
+use std::os::unix::io::{RawFd, FromRawFd};
+
+use libc;
+use rotor::mio::tcp::TcpListener;
+use rotor::mio::unix::UnixListener;
+
+use {Listener};
+
+
+/// First file descriptor passed by systemd
+pub const LISTEN_FDS_START: RawFd = 3;
+
+
+/// Returns listening sockets passed by systemd socket activation
+///
+/// Each socket is returned with it's name from `LISTEN_FDNAMES` (which is
+/// set by systemd from `FileDescriptorName=` of the socket unit). Empty
+/// list is returned if sockets are not passed (or passed to another
+/// process, i.e. `LISTEN_PID` doesn't match).
+///
+/// Sockets are transferred into the ownership of returned listeners, so
+/// you should call this function only once. On error all the passed
+/// sockets are closed. The environment variables are
+/// unset if `unset_environment` is true, so that they are not inherited by
+/// child processes and subsequent calls return empty list.
+///
+/// Typical usage:
+///
+/// ```ignore
+/// for (_name, listener) in systemd_listeners(true).unwrap() {
+///     loop_creator.add_machine_with(|scope| {
+///         Accept::<Stream<Http>, _>::new(listener, (), scope)
+///     }).unwrap();
+/// }
+/// ```
+pub fn systemd_listeners(unset_environment: bool)
+    -> io::Result<Vec<(Option<String>, Listener)>>
+{
+    let fds = try!(parse_listen_fds(
+        env::var("LISTEN_PID").ok().as_ref().map(|x| &x[..]),
+        env::var("LISTEN_FDS").ok().as_ref().map(|x| &x[..]),
+        env::var("LISTEN_FDNAMES").ok().as_ref().map(|x| &x[..]),
+        unsafe { libc::getpid() } as u32));
+    if unset_environment {
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+    }
+    let mut result = Vec::with_capacity(fds.len());
+    for (i, &(fd, ref name)) in fds.iter().enumerate() {
+        // Descriptors passed by systemd don't have close-on-exec flag set.
+        // Listeners which are already created are closed on drop.
+        if let Err(e) = set_cloexec(fd) {
+            close_all(fds[i..].iter().map(|&(fd, _)| fd));
+            return Err(e);
+        }
+        // The descriptor itself is closed by `listener_from_raw_fd`
+        match unsafe { listener_from_raw_fd(fd) } {
+            Ok(lst) => result.push((name.clone(), lst)),
+            Err(e) => {
+                close_all(fds[i+1..].iter().map(|&(fd, _)| fd));
+                return Err(e);
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Creates listeners from a list of inherited file descriptors
+///
+/// This is useful for zero-downtime restarts, when old process passes
+/// listening sockets to a new one. Type of the listener (TCP or Unix) is
+/// detected automatically.
+///
+/// This function is unsafe for the same reasons as `FromRawFd`: the
+/// listeners take ownership of the file descriptors. On error all the
+/// descriptors are closed.
+pub unsafe fn listeners_from_raw_fds(fds: &[RawFd])
+    -> io::Result<Vec<Listener>>
+{
+    let mut result = Vec::with_capacity(fds.len());
+    for (i, &fd) in fds.iter().enumerate() {
+        match listener_from_raw_fd(fd) {
+            Ok(lst) => result.push(lst),
+            Err(e) => {
+                close_all(fds[i+1..].iter().cloned());
+                return Err(e);
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Creates a listener from a file descriptor detecting it's type
+///
+/// The socket must be a stream socket in a listening state, and either
+/// TCP (IPv4 or IPv6) or Unix socket. It's switched into non-blocking mode.
+///
+/// This function is unsafe for the same reasons as `FromRawFd`: the
+/// listener takes ownership of the file descriptor. The descriptor is
+/// closed if it can't be used as a listener.
+pub unsafe fn listener_from_raw_fd(fd: RawFd) -> io::Result<Listener> {
+    let family = match socket_family(fd) {
+        Ok(family) => family,
+        Err(e) => {
+            libc::close(fd);
+            return Err(e);
+        }
+    };
+    match family {
+        libc::AF_INET | libc::AF_INET6 => {
+            let lst = net::TcpListener::from_raw_fd(fd);
+            let addr = try!(lst.local_addr());
+            // This also sets non-blocking mode
+            TcpListener::from_listener(lst, &addr).map(Listener::Tcp)
+        }
+        libc::AF_UNIX => {
+            let lst = UnixListener::from_raw_fd(fd);
+            try!(set_nonblocking(fd));
+            Ok(Listener::Unix(lst))
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Checks that fd is a listening stream socket and returns it's family
+unsafe fn socket_family(fd: RawFd) -> io::Result<libc::c_int> {
+    if try!(getsockopt_int(fd, libc::SO_TYPE)) != libc::SOCK_STREAM {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "file descriptor is not a stream socket"));
+    }
+    if try!(getsockopt_int(fd, libc::SO_ACCEPTCONN)) == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "socket is not listening"));
+    }
+    let mut addr: libc::sockaddr_storage = mem::zeroed();
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    if libc::getsockname(fd,
+        &mut addr as *mut _ as *mut libc::sockaddr, &mut len) < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    match addr.ss_family as libc::c_int {
+        family @ libc::AF_INET | family @ libc::AF_INET6 |
+        family @ libc::AF_UNIX => Ok(family),
+        _ => {
+            Err(io::Error::new(io::ErrorKind::InvalidInput,
+                "unsupported socket family"))
+        }
+    }
+}
+
+fn parse_listen_fds(pid: Option<&str>, fds: Option<&str>,
+    names: Option<&str>, my_pid: u32)
+    -> io::Result<Vec<(RawFd, Option<String>)>>
+{
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidInput, msg);
+    match pid.map(|x| x.parse::<u32>()) {
+        None => return Ok(Vec::new()),
+        Some(Ok(pid)) if pid == my_pid => {}
+        Some(Ok(_)) => return Ok(Vec::new()),
+        Some(Err(_)) => return Err(invalid("invalid LISTEN_PID")),
+    }
+    let num = match fds.map(|x| x.parse::<RawFd>()) {
+        None => return Ok(Vec::new()),
+        Some(Ok(num)) if num >= 0 => num,
+        Some(_) => return Err(invalid("invalid LISTEN_FDS")),
+    };
+    let names = names.map(|x| x.split(':').collect::<Vec<_>>());
+    if names.as_ref().map(|x| x.len() != num as usize).unwrap_or(false) {
+        return Err(invalid("LISTEN_FDNAMES doesn't match LISTEN_FDS"));
+    }
+    Ok((0..num).map(|i| {
+        let name = names.as_ref().map(|x| x[i as usize].to_string());
+        (LISTEN_FDS_START + i, name)
+    }).collect())
+}
+
+unsafe fn getsockopt_int(fd: RawFd, opt: libc::c_int)
+    -> io::Result<libc::c_int>
+{
+    let mut val: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    if libc::getsockopt(fd, libc::SOL_SOCKET, opt,
+        &mut val as *mut _ as *mut libc::c_void, &mut len) < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(val)
+}
+
+fn close_all<I: Iterator<Item=RawFd>>(fds: I) {
+    for fd in fds {
+        unsafe { libc::close(fd) };
+    }
+}
+
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 ||
+            libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn set_cloexec(fd: RawFd) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        if flags < 0 ||
+            libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io;
+    use std::env;
+    use std::net;
+    use std::process;
+    use std::process::Command;
+    use std::os::unix::io::{AsRawFd, IntoRawFd};
+    use std::os::unix::net::{UnixDatagram, UnixListener};
+    use std::os::unix::process::CommandExt;
+
+    use libc;
+    use super::{parse_listen_fds, listener_from_raw_fd, systemd_listeners};
+    use {Listener};
+
+    #[test]
+    fn no_env() {
+        assert_eq!(parse_listen_fds(None, None, None, 10).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn other_pid() {
+        assert_eq!(parse_listen_fds(Some("11"), Some("2"), None, 10)
+                   .unwrap(), vec![]);
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(parse_listen_fds(Some("10"), Some("2"),
+                                    Some("http:admin"), 10).unwrap(),
+                   vec![(3, Some("http".to_string())),
+                        (4, Some("admin".to_string()))]);
+    }
+
+    #[test]
+    fn names_mismatch() {
+        assert!(parse_listen_fds(Some("10"), Some("3"),
+                                 Some("http:admin"), 10).is_err());
+        assert!(parse_listen_fds(Some("10"), Some("1"),
+                                 Some("http:admin"), 10).is_err());
+    }
+
+    #[test]
+    fn bad_fds() {
+        assert!(parse_listen_fds(Some("10"), Some("x"), None, 10).is_err());
+    }
+
+    #[test]
+    fn detect_tcp() {
+        let lst = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        match unsafe { listener_from_raw_fd(lst.into_raw_fd()) } {
+            Ok(Listener::Tcp(_)) => {}
+            x => panic!("Wrong listener {:?}", x),
+        }
+    }
+
+    #[test]
+    fn detect_unix() {
+        let path = env::temp_dir()
+            .join(format!("rotor-stream-test-{}.sock", process::id()));
+        fs::remove_file(&path).ok();
+        let lst = UnixListener::bind(&path).unwrap();
+        fs::remove_file(&path).ok();
+        match unsafe { listener_from_raw_fd(lst.into_raw_fd()) } {
+            Ok(Listener::Unix(_)) => {}
+            x => panic!("Wrong listener {:?}", x),
+        }
+    }
+
+    // Descriptors are closed by `listener_from_raw_fd` on error
+    #[test]
+    fn detect_not_listening() {
+        let sock = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(unsafe { listener_from_raw_fd(sock.into_raw_fd()) }.is_err());
+        let (a, _b) = UnixDatagram::pair().unwrap();
+        assert!(unsafe { listener_from_raw_fd(a.into_raw_fd()) }.is_err());
+    }
+
+    // Runs in the child process spawned by `systemd_activation`
+    #[test]
+    fn systemd_child() {
+        let addr = match env::var("ROTOR_STREAM_TEST_ADDR") {
+            Ok(addr) => addr,
+            Err(_) => return,  // not in the child process
+        };
+        let listeners = systemd_listeners(true).unwrap();
+        assert_eq!(listeners.len(), 1);
+        match listeners[0] {
+            (Some(ref name), Listener::Tcp(ref lst)) => {
+                assert_eq!(name, "http");
+                assert_eq!(lst.local_addr().unwrap().to_string(), addr);
+            }
+            ref x => panic!("Wrong listener {:?}", x),
+        }
+        assert!(env::var("LISTEN_FDS").is_err());
+        assert_eq!(systemd_listeners(true).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn systemd_activation() {
+        let lst = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let fd = lst.as_raw_fd();
+        // The shell sets LISTEN_PID to it's own pid, which is retained by
+        // `exec`, the same way systemd does it
+        let mut cmd = Command::new("/bin/sh");
+        cmd
+            .arg("-c")
+            .arg("LISTEN_PID=$$ exec \"$0\" \"$@\"")
+            .arg(env::current_exe().unwrap())
+            .args(["--exact", "activation::test::systemd_child",
+                   "--test-threads=1", "--nocapture"])
+            .env("ROTOR_STREAM_TEST_ADDR",
+                 lst.local_addr().unwrap().to_string())
+            .env("LISTEN_FDS", "1")
+            .env("LISTEN_FDNAMES", "http");
+        unsafe {
+            cmd.pre_exec(move || {
+                // Both calls clear close-on-exec flag for the descriptor 3
+                let res = if fd == 3 {
+                    libc::fcntl(fd, libc::F_SETFD, 0)
+                } else {
+                    libc::dup2(fd, 3)
+                };
+                if res < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let output = cmd.output().unwrap();
+        assert!(output.status.success(), "child failed: {}",
+            String::from_utf8_lossy(&output.stdout));
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.contains("1 passed"), "child output: {}", stdout);
+    }
+}
@@ -0,0 +1,146 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use rotor::Notifier;
+
+use {Drain};
+
+
+pub struct DrainState {
+    started: AtomicBool,
+    connections: AtomicUsize,
+    servers: Mutex<Vec<Notifier>>,
+}
+
+impl Drain {
+    pub fn new() -> Drain {
+        Drain(Arc::new(DrainState {
+            started: AtomicBool::new(false),
+            connections: AtomicUsize::new(0),
+            servers: Mutex::new(Vec::new()),
+        }))
+    }
+    /// Start draining
+    ///
+    /// This may be called from any thread. Calling it multiple times is
+    /// harmless.
+    pub fn start(&self) {
+        self.0.started.store(true, Ordering::SeqCst);
+        for notifier in self.0.servers.lock().unwrap().drain(..) {
+            notifier.wakeup().ok();
+        }
+    }
+    /// Returns true if `start()` was called for this handle
+    pub fn is_draining(&self) -> bool {
+        self.0.started.load(Ordering::SeqCst)
+    }
+    /// Returns number of connections that are still alive
+    ///
+    /// Only connections accepted by servers created with this handle are
+    /// counted
+    pub fn connections(&self) -> usize {
+        self.0.connections.load(Ordering::SeqCst)
+    }
+}
+
+impl Default for Drain {
+    fn default() -> Drain {
+        Drain::new()
+    }
+}
+
+impl fmt::Debug for Drain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Drain")
+            .field("draining", &self.is_draining())
+            .field("connections", &self.connections())
+            .finish()
+    }
+}
+
+pub fn add_server(drain: &Drain, notifier: Notifier) {
+    if drain.is_draining() {
+        notifier.wakeup().ok();
+    } else {
+        drain.0.servers.lock().unwrap().push(notifier);
+    }
+}
+
+pub fn connection_opened(drain: &Drain) {
+    drain.0.connections.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Returns true if this was the last connection and draining is started
+pub fn connection_closed(drain: &Drain) -> bool {
+    let left = drain.0.connections.fetch_sub(1, Ordering::SeqCst) - 1;
+    left == 0 && drain.is_draining()
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use std::time::Duration;
+    use std::sync::mpsc::channel;
+    use std::io::{Read, Write};
+    use std::net::TcpStream as StdStream;
+
+    use rotor::{Loop, Config};
+    use rotor::mio::tcp::{TcpListener, TcpStream};
+    use testing::{pair, MockSocket, StreamDriver};
+    use testing::echo::Echo;
+    use {Accept, Drain, Stream, StreamOptions};
+
+    #[test]
+    fn is_draining() {
+        let drain = Drain::new();
+        let (sock, _peer) = pair();
+        let options = StreamOptions::new().drain(&drain);
+        let mut driver = StreamDriver::<Echo<MockSocket>>::with_options(
+            sock, (), options, ());
+        assert!(!driver.transport().unwrap().is_draining());
+        drain.start();
+        assert!(driver.transport().unwrap().is_draining());
+    }
+
+    fn echo(client: &mut StdStream, line: &[u8]) {
+        client.write_all(line).unwrap();
+        let mut buf = vec![0u8; line.len()];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], line);
+    }
+
+    #[test]
+    fn shutdown_after_last_connection() {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap())
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let drain = Drain::new();
+        let handle = drain.clone();
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let mut lp = Loop::new(&Config::new()).unwrap();
+            lp.add_machine_with(|scope| {
+                Accept::<Stream<Echo<TcpStream>>, _>::with_drain(
+                    listener, (), &handle, scope)
+            }).unwrap();
+            lp.run(()).unwrap();
+            tx.send(()).unwrap();
+        });
+        let mut client = StdStream::connect(addr).unwrap();
+        echo(&mut client, b"hello\n");
+
+        drain.start();
+        let refused = (0..100).any(|_| {
+            thread::sleep(Duration::from_millis(10));
+            StdStream::connect(addr).is_err()
+        });
+        assert!(refused);
+        // The connection accepted before is still served
+        echo(&mut client, b"world\n");
+        assert!(rx.try_recv().is_err());
+        drop(client);
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(drain.connections(), 0);
+    }
+}
@@ -17,3 +17,20 @@ impl Error for ProtocolStop {
         r#"Protocol returned None (which means "stop") at start"#
     }
 }
+
+/// PROXY protocol header is malformed or unsupported
+#[derive(Debug)]
+pub struct InvalidProxyHeader(pub &'static str);
+
+impl fmt::Display for InvalidProxyHeader {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "InvalidProxyHeader: {}", self.0)
+    }
+}
+
+impl Error for InvalidProxyHeader {
+    fn cause(&self) -> Option<&Error> { None }
+    fn description(&self) -> &'static str {
+        "PROXY protocol header is invalid"
+    }
+}
@@ -0,0 +1,738 @@
+use std::io;
+use std::fmt;
+use std::cmp::min;
+use std::error::Error;
+use std::marker::PhantomData;
+#[cfg(target_os="linux")] use std::io::ErrorKind::{WouldBlock, WriteZero};
+#[cfg(target_os="linux")] use std::os::unix::io::RawFd;
+
+use rotor::{Machine, Response, Scope, EventSet, PollOpt};
+#[cfg(target_os="linux")] use rotor::Time;
+use rotor::void::{unreachable, Void};
+
+use stream::{self, Closed};
+use extensions::{ScopeExt, ResponseExt};
+#[cfg(target_os="linux")] use metrics::report;
+use {Stream, StreamSocket, StreamOptions, Stats};
+use {Protocol, Intent, Transport, Exception};
+
+
+/// Default number of bytes buffered in each direction by `Forward`
+///
+/// This is the default size of the pipe on linux.
+pub const FORWARD_BUFFER: usize = 65536;
+
+
+/// A state machine which moves bytes between two streams in both directions
+///
+/// Both sockets are wrapped into a `Stream`, so `StreamOptions` (rate
+/// limits, idle timeout, budgets), statistics and metrics apply to each of
+/// them. When either stream is closed, for example on error or idle
+/// timeout, the other one is closed too.
+///
+/// Both sockets are registered with the token of the state machine, so
+/// it's unknown which of them an event belongs to. On every event both
+/// streams try to read and write, i.e. reads and writes which are known to
+/// block are not skipped like for a standalone `Stream`, and would-block
+/// counters in `Stats` are higher.
+///
+/// At most `buffer_size` bytes are kept in the output buffer of each
+/// stream. When the buffer is full the other stream doesn't read until the
+/// data is flushed, so the fast peer is slowed down to the speed of the
+/// slow one.
+///
+/// When a peer closes its sending side, and all the data received from it
+/// is flushed, the other socket is shut down for writing (see
+/// `SocketError::shutdown_write`), so half-close is propagated. The state
+/// machine is finished when both directions are closed.
+///
+/// On linux, if both sockets provide `SocketError::zero_copy_fd` and there
+/// are no rate limits for the direction, the data is moved by `splice`
+/// system call through a pipe, without copying it to the user space.
+pub struct Forward<C, A: StreamSocket, B: StreamSocket> {
+    a: Stream<Pump<C, A>>,
+    b: Stream<Pump<C, B>>,
+    a_to_b: Direction,
+    b_to_a: Direction,
+}
+
+/// The protocol of the streams joined by `Forward`
+///
+/// It doesn't look into the data, `Forward` moves it between the buffers of
+/// the streams and tells the pump when to read more.
+pub struct Pump<C, S> {
+    read: bool,
+    eof: bool,
+    phantom: PhantomData<(C, S)>,
+}
+
+struct Direction {
+    limit: usize,
+    shutdown: bool,
+    bytes: u64,
+    #[cfg(target_os="linux")]
+    pipe: Option<KernelPipe>,
+}
+
+#[cfg(target_os="linux")]
+struct KernelPipe {
+    read: RawFd,
+    write: RawFd,
+    len: usize,
+}
+
+impl<C, S> Pump<C, S> {
+    fn next(self, transport: &mut Transport<S>) -> Intent<Pump<C, S>>
+        where S: StreamSocket
+    {
+        if self.read && !self.eof {
+            let bytes = transport.input().len() + 1;
+            Intent::of(self).expect_bytes(bytes)
+        } else {
+            Intent::of(self).sleep()
+        }
+    }
+}
+
+impl<C, S: StreamSocket> Protocol for Pump<C, S> {
+    type Context = C;
+    type Socket = S;
+    type Seed = ();
+    fn create(_seed: (), _sock: &mut S, _scope: &mut Scope<C>)
+        -> Intent<Self>
+    {
+        Intent::of(Pump { read: false, eof: false, phantom: PhantomData })
+            .sleep()
+    }
+    fn bytes_read(mut self, _transport: &mut Transport<S>,
+        _end: usize, _scope: &mut Scope<C>)
+        -> Intent<Self>
+    {
+        // The data is moved by `Forward` which asks for more when there
+        // is room for it
+        self.read = false;
+        Intent::of(self).sleep()
+    }
+    fn bytes_flushed(self, transport: &mut Transport<S>,
+        _scope: &mut Scope<C>)
+        -> Intent<Self>
+    {
+        self.next(transport)
+    }
+    fn timeout(self, _transport: &mut Transport<S>, _scope: &mut Scope<C>)
+        -> Intent<Self>
+    {
+        // The pump never sets a deadline, so this is the idle timeout
+        debug!("Forwarded connection is idle for too long");
+        Intent::done()
+    }
+    fn exception(mut self, _transport: &mut Transport<S>,
+        reason: Exception, _scope: &mut Scope<C>)
+        -> Intent<Self>
+    {
+        match reason {
+            Exception::EndOfStream => {
+                self.eof = true;
+                Intent::of(self).sleep()
+            }
+            reason => {
+                debug!("Forwarded connection closed: {}", reason);
+                Intent::done()
+            }
+        }
+    }
+    fn fatal(self, reason: Exception, _scope: &mut Scope<C>)
+        -> Option<Box<Error>>
+    {
+        debug!("Forwarded connection closed: {}", reason);
+        None
+    }
+    fn wakeup(self, transport: &mut Transport<S>, _scope: &mut Scope<C>)
+        -> Intent<Self>
+    {
+        self.next(transport)
+    }
+}
+
+/// Registers the socket and wraps it into a stream for `Forward`
+pub fn open<C, S: StreamSocket>(sock: S, options: StreamOptions,
+    scope: &mut Scope<C>)
+    -> Result<Stream<Pump<C, S>>, Box<Error>>
+{
+    try!(scope.register(&sock, EventSet::all(), PollOpt::edge()));
+    stream::create(sock, (), false, options, scope)
+}
+
+/// Asks the stream to read more bytes
+///
+/// This is used by `Proxy` to receive the data for the inspection.
+pub fn read_more<C, S: StreamSocket>(mut stream: Stream<Pump<C, S>>,
+    scope: &mut Scope<C>)
+    -> Result<Stream<Pump<C, S>>, Closed>
+{
+    stream.fsm.read = true;
+    stream::wakeup(stream, scope)
+}
+
+/// Returns true if the peer closed its sending side
+pub fn is_eof<C, S: StreamSocket>(stream: &Stream<Pump<C, S>>) -> bool {
+    stream.fsm.eof
+}
+
+/// Joins two streams, the data already in the input buffers is forwarded
+/// first
+pub fn join<C, A, B>(a: Stream<Pump<C, A>>, b: Stream<Pump<C, B>>,
+    buffer_size: usize, scope: &mut Scope<C>)
+    -> Response<Forward<C, A, B>, Void>
+    where A: StreamSocket, B: StreamSocket
+{
+    assert!(buffer_size > 0);
+    let fwd = Forward {
+        a_to_b: Direction::new(&a, &b, buffer_size),
+        b_to_a: Direction::new(&b, &a, buffer_size),
+        a: a,
+        b: b,
+    };
+    // There will be no edge-triggered event for the data which is already
+    // in the buffers, so start right now
+    to_response(fwd.balance(scope))
+}
+
+impl<C, A: StreamSocket, B: StreamSocket> Forward<C, A, B> {
+    /// Forward data between two sockets with default options
+    pub fn new(a: A, b: B, scope: &mut Scope<C>) -> Response<Self, Void> {
+        Forward::with_options(a, StreamOptions::new(), b, StreamOptions::new(),
+            FORWARD_BUFFER, scope)
+    }
+    /// Forward data between two sockets
+    ///
+    /// The options are applied to the stream of the respective socket.
+    /// At most `buffer_size` bytes are buffered in each direction.
+    ///
+    /// # Panics
+    ///
+    /// When `buffer_size` is zero.
+    pub fn with_options(a: A, a_options: StreamOptions,
+        b: B, b_options: StreamOptions, buffer_size: usize,
+        scope: &mut Scope<C>)
+        -> Response<Self, Void>
+    {
+        let a = match open(a, a_options, scope) {
+            Ok(a) => a,
+            Err(e) => return Response::error(e),
+        };
+        let b = match open(b, b_options, scope) {
+            Ok(b) => b,
+            Err(e) => {
+                stream::abort(a);
+                return Response::error(e);
+            }
+        };
+        join(a, b, buffer_size, scope)
+    }
+    /// Number of bytes forwarded (from first to second, from second to
+    /// first)
+    ///
+    /// This counts the bytes passed to the output buffer of the other
+    /// stream (or written to its socket when `splice` is used).
+    pub fn forwarded(&self) -> (u64, u64) {
+        (self.a_to_b.bytes, self.b_to_a.bytes)
+    }
+    /// Returns true if data is moved by `splice` system call
+    pub fn is_zero_copy(&self) -> bool {
+        self.a_to_b.is_zero_copy() && self.b_to_a.is_zero_copy()
+    }
+    /// Statistics of the first and the second stream
+    pub fn stats(&self) -> (&Stats, &Stats) {
+        (&self.a.stats, &self.b.stats)
+    }
+    fn update<F, G>(self, scope: &mut Scope<C>, f: F, g: G)
+        -> Response<Self, Void>
+        where F: FnOnce(Stream<Pump<C, A>>, &mut Scope<C>)
+                    -> Result<Stream<Pump<C, A>>, Closed>,
+              G: FnOnce(Stream<Pump<C, B>>, &mut Scope<C>)
+                    -> Result<Stream<Pump<C, B>>, Closed>,
+    {
+        let Forward { a, b, a_to_b, b_to_a } = self;
+        let a = match f(a, scope) {
+            Ok(a) => a,
+            Err((err, _)) => {
+                stream::abort(b);
+                return to_response(Err(err));
+            }
+        };
+        let b = match g(b, scope) {
+            Ok(b) => b,
+            Err((err, _)) => {
+                stream::abort(a);
+                return to_response(Err(err));
+            }
+        };
+        let fwd = Forward { a: a, b: b, a_to_b: a_to_b, b_to_a: b_to_a };
+        to_response(fwd.balance(scope))
+    }
+    // Moves the data and kicks the streams until nothing changes. The error
+    // is `None` when the state machine is finished normally.
+    fn balance(self, scope: &mut Scope<C>)
+        -> Result<Self, Option<Box<Error>>>
+    {
+        let Forward { mut a, mut b, mut a_to_b, mut b_to_a } = self;
+        loop {
+            let res = a_to_b.pump(&mut a, &mut b, scope)
+                .and_then(|to_b| b_to_a.pump(&mut b, &mut a, scope)
+                                  .map(|to_a| (to_b, to_a)));
+            let (to_b, to_a) = match res {
+                Ok(moved) => moved,
+                Err(e) => {
+                    debug!("Forwarded connection closed: {}", e);
+                    stream::abort(a);
+                    stream::abort(b);
+                    return Err(None);
+                }
+            };
+            let (a_read, b_read) = (a.stats.bytes_read, b.stats.bytes_read);
+            // Flush the new data and read more if there is room for it
+            let read = a_to_b.wants_read(&a, &b);
+            if to_a || (read && !a.fsm.read) {
+                a.fsm.read = read;
+                a = match stream::wakeup(a, scope) {
+                    Ok(a) => a,
+                    Err((err, _)) => {
+                        stream::abort(b);
+                        return Err(err);
+                    }
+                };
+            }
+            let read = b_to_a.wants_read(&b, &a);
+            if to_b || (read && !b.fsm.read) {
+                b.fsm.read = read;
+                b = match stream::wakeup(b, scope) {
+                    Ok(b) => b,
+                    Err((err, _)) => {
+                        stream::abort(a);
+                        return Err(err);
+                    }
+                };
+            }
+            let res = a_to_b.propagate_eof(&a, &b)
+                .and_then(|()| b_to_a.propagate_eof(&b, &a));
+            if let Err(e) = res {
+                debug!("Can't propagate half-close: {}", e);
+                stream::abort(a);
+                stream::abort(b);
+                return Err(None);
+            }
+            if a_to_b.shutdown && b_to_a.shutdown {
+                stream::abort(a);
+                stream::abort(b);
+                return Err(None);
+            }
+            let progress = to_a || to_b ||
+                a.stats.bytes_read != a_read || b.stats.bytes_read != b_read;
+            if !progress {
+                return Ok(Forward { a: a, b: b,
+                                    a_to_b: a_to_b, b_to_a: b_to_a });
+            }
+        }
+    }
+}
+
+fn to_response<C, A, B>(res: Result<Forward<C, A, B>, Option<Box<Error>>>)
+    -> Response<Forward<C, A, B>, Void>
+    where A: StreamSocket, B: StreamSocket
+{
+    match res {
+        Ok(fwd) => {
+            let dline = [stream::timer(&fwd.a), stream::timer(&fwd.b)]
+                .iter().filter_map(|x| *x).min();
+            Response::ok(fwd).deadline_opt(dline)
+        }
+        Err(Some(e)) => Response::error(e),
+        Err(None) => Response::done(),
+    }
+}
+
+// Dispatches the timer of the stream if it's expired
+fn expire<P: Protocol>(stream: Stream<P>, scope: &mut Scope<P::Context>)
+    -> Result<Stream<P>, Closed>
+{
+    if scope.reached(stream::timer(&stream)) {
+        stream::timer_expired(stream, scope)
+    } else {
+        Ok(stream)
+    }
+}
+
+impl Direction {
+    fn new<C, S, D>(src: &Stream<Pump<C, S>>, dst: &Stream<Pump<C, D>>,
+        limit: usize)
+        -> Direction
+        where S: StreamSocket, D: StreamSocket
+    {
+        Direction {
+            limit: limit,
+            shutdown: false,
+            bytes: 0,
+            #[cfg(target_os="linux")]
+            pipe: KernelPipe::new(src, dst),
+        }
+    }
+    #[cfg(target_os="linux")]
+    fn is_zero_copy(&self) -> bool {
+        self.pipe.is_some()
+    }
+    #[cfg(not(target_os="linux"))]
+    fn is_zero_copy(&self) -> bool {
+        false
+    }
+    #[cfg(target_os="linux")]
+    fn in_pipe(&self) -> usize {
+        self.pipe.as_ref().map(|p| p.len).unwrap_or(0)
+    }
+    #[cfg(not(target_os="linux"))]
+    fn in_pipe(&self) -> usize {
+        0
+    }
+    // Moves the data from `src` to `dst`, returns true if anything is moved
+    fn pump<C, S, D>(&mut self, src: &mut Stream<Pump<C, S>>,
+        dst: &mut Stream<Pump<C, D>>, scope: &mut Scope<C>)
+        -> io::Result<bool>
+        where S: StreamSocket, D: StreamSocket
+    {
+        let room = self.limit.saturating_sub(dst.outbuf.len());
+        let bytes = min(room, src.inbuf.len());
+        if bytes > 0 {
+            dst.outbuf.extend(&src.inbuf[..bytes]);
+            src.inbuf.consume(bytes);
+            self.bytes += bytes as u64;
+        }
+        let spliced = try!(self.splice(src, dst, scope));
+        Ok(bytes > 0 || spliced)
+    }
+    #[cfg(target_os="linux")]
+    fn splice<C, S, D>(&mut self, src: &mut Stream<Pump<C, S>>,
+        dst: &mut Stream<Pump<C, D>>, scope: &mut Scope<C>)
+        -> io::Result<bool>
+        where S: StreamSocket, D: StreamSocket
+    {
+        let pipe = match self.pipe {
+            Some(ref mut pipe) => pipe,
+            None => return Ok(false),
+        };
+        let (src_fd, dst_fd) = match (src.socket.zero_copy_fd(),
+                                      dst.socket.zero_copy_fd())
+        {
+            (Some(src_fd), Some(dst_fd)) => (src_fd, dst_fd),
+            _ => return Ok(false),
+        };
+        let now = scope.now();
+        let mut moved = false;
+        loop {
+            // The data in the output buffer goes first
+            while pipe.len > 0 && dst.outbuf.len() == 0 {
+                dst.stats.write_calls += 1;
+                match splice(pipe.read, dst_fd, pipe.len) {
+                    Ok(0) => {
+                        return Err(io::Error::new(WriteZero,
+                            "failed to forward data"));
+                    }
+                    Ok(bytes) => {
+                        pipe.len -= bytes;
+                        self.bytes += bytes as u64;
+                        moved = true;
+                        report(|m| m.bytes_written(bytes));
+                        dst.stats.bytes_written += bytes as u64;
+                        transferred(dst, now);
+                    }
+                    Err(ref e) if e.kind() == WouldBlock => {
+                        dst.stats.write_would_block += 1;
+                        break;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            // Data read before splicing is started (e.g. by `Proxy`) is
+            // moved through the buffers
+            if src.fsm.eof || src.inbuf.len() > 0 || pipe.len >= self.limit {
+                return Ok(moved);
+            }
+            src.stats.read_calls += 1;
+            match splice(src_fd, pipe.write, self.limit - pipe.len) {
+                Ok(0) => {
+                    src.fsm.eof = true;
+                    return Ok(true);
+                }
+                Ok(bytes) => {
+                    pipe.len += bytes;
+                    moved = true;
+                    report(|m| m.bytes_read(bytes));
+                    src.stats.bytes_read += bytes as u64;
+                    transferred(src, now);
+                }
+                Err(ref e) if e.kind() == WouldBlock => {
+                    src.stats.read_would_block += 1;
+                    return Ok(moved);
+                }
+                Err(ref e) if pipe.len == 0 && is_unsupported(e) => {
+                    debug!("Can't splice, forwarding through buffers: {}", e);
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        // The pipe is empty, so nothing is lost when it's dropped, and the
+        // data is read into the buffer of the stream from now on
+        self.pipe = None;
+        Ok(moved)
+    }
+    #[cfg(not(target_os="linux"))]
+    fn splice<C, S, D>(&mut self, _src: &mut Stream<Pump<C, S>>,
+        _dst: &mut Stream<Pump<C, D>>, _scope: &mut Scope<C>)
+        -> io::Result<bool>
+        where S: StreamSocket, D: StreamSocket
+    {
+        Ok(false)
+    }
+    fn wants_read<C, S, D>(&self, src: &Stream<Pump<C, S>>,
+        dst: &Stream<Pump<C, D>>)
+        -> bool
+        where S: StreamSocket, D: StreamSocket
+    {
+        !src.fsm.eof && !self.is_zero_copy() &&
+            src.inbuf.len() == 0 && dst.outbuf.len() < self.limit
+    }
+    // Shuts down writing side of `dst` when everything from `src` is
+    // flushed
+    fn propagate_eof<C, S, D>(&mut self, src: &Stream<Pump<C, S>>,
+        dst: &Stream<Pump<C, D>>)
+        -> io::Result<()>
+        where S: StreamSocket, D: StreamSocket
+    {
+        if !self.shutdown && src.fsm.eof && src.inbuf.len() == 0 &&
+            self.in_pipe() == 0 && dst.outbuf.len() == 0
+        {
+            self.shutdown = true;
+            try!(dst.socket.shutdown_write());
+        }
+        Ok(())
+    }
+}
+
+// Re-arms the idle timer, like the stream does after reading or writing
+#[cfg(target_os="linux")]
+fn transferred<P: Protocol>(stream: &mut Stream<P>, now: Time) {
+    if let Some(timeout) = stream.options.idle_timeout {
+        stream.idle = Some(now + timeout);
+    }
+}
+
+#[cfg(target_os="linux")]
+impl KernelPipe {
+    fn new<C, S, D>(src: &Stream<Pump<C, S>>, dst: &Stream<Pump<C, D>>)
+        -> Option<KernelPipe>
+        where S: StreamSocket, D: StreamSocket
+    {
+        use libc;
+
+        // Rate limits are applied by the stream when it reads and writes
+        if src.read_limit.is_some() || dst.write_limit.is_some() {
+            return None;
+        }
+        if src.socket.zero_copy_fd().is_none() ||
+            dst.socket.zero_copy_fd().is_none()
+        {
+            return None;
+        }
+        let mut fds = [0; 2];
+        let res = unsafe {
+            libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC)
+        };
+        if res != 0 {
+            debug!("Can't create pipe, splice is disabled: {}",
+                io::Error::last_os_error());
+            return None;
+        }
+        Some(KernelPipe { read: fds[0], write: fds[1], len: 0 })
+    }
+}
+
+#[cfg(target_os="linux")]
+fn splice(src: RawFd, dst: RawFd, max: usize) -> io::Result<usize> {
+    use std::ptr;
+    use libc;
+
+    let res = unsafe {
+        libc::splice(src, ptr::null_mut(), dst, ptr::null_mut(), max,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK)
+    };
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res as usize)
+    }
+}
+
+// The socket (or the kernel) doesn't support splice
+#[cfg(target_os="linux")]
+fn is_unsupported(err: &io::Error) -> bool {
+    use libc;
+
+    match err.raw_os_error() {
+        Some(libc::EINVAL) | Some(libc::ENOSYS) => true,
+        _ => false,
+    }
+}
+
+#[cfg(target_os="linux")]
+impl Drop for KernelPipe {
+    fn drop(&mut self) {
+        use libc;
+
+        unsafe {
+            libc::close(self.read);
+            libc::close(self.write);
+        }
+    }
+}
+
+impl<C, A: StreamSocket, B: StreamSocket> Machine for Forward<C, A, B> {
+    type Context = C;
+    type Seed = Void;
+    fn create(void: Void, _scope: &mut Scope<C>) -> Response<Self, Void> {
+        unreachable(void);
+    }
+    fn ready(self, _events: EventSet, scope: &mut Scope<C>)
+        -> Response<Self, Void>
+    {
+        // Both sockets share the token, so it's unknown which one is
+        // ready. Both streams try to read and write, and hang up is not
+        // passed to not close the wrong stream: it's reported by the reads
+        // and writes anyway.
+        let events = EventSet::readable() | EventSet::writable();
+        self.update(scope,
+            |a, scope| stream::ready(a, events, scope),
+            |b, scope| stream::ready(b, events, scope))
+    }
+    fn spawned(self, _scope: &mut Scope<C>) -> Response<Self, Void> {
+        unreachable!();
+    }
+    fn timeout(self, scope: &mut Scope<C>) -> Response<Self, Void> {
+        self.update(scope, expire, expire)
+    }
+    fn wakeup(self, scope: &mut Scope<C>) -> Response<Self, Void> {
+        self.update(scope, stream::wakeup, stream::wakeup)
+    }
+}
+
+impl<C, A: StreamSocket, B: StreamSocket> fmt::Debug for Forward<C, A, B> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Forward{:?}", self.forwarded())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use rotor::{Machine, EventSet, Time};
+    use testing::{pair, with_scope};
+    use super::Forward;
+    use {StreamOptions};
+
+    #[test]
+    fn backpressure() {
+        let (a, a_peer) = pair();
+        let (b, b_peer) = pair();
+        let options = StreamOptions::new();
+        with_scope(&mut (), Time::zero(), |scope| {
+            let fwd = Forward::with_options(a, options.clone(),
+                                            b, options, 4, scope)
+                .expect_machine();
+            assert!(!fwd.is_zero_copy());
+            a_peer.push(b"hello world");
+            b_peer.block_writes(true);
+            let fwd = fwd.ready(EventSet::readable(), scope)
+                .expect_machine();
+            assert_eq!(b_peer.output(), b"");
+            // The rest is not read until there is room for it
+            let reads = a_peer.read_calls();
+            let fwd = fwd.ready(EventSet::readable(), scope)
+                .expect_machine();
+            assert_eq!(a_peer.read_calls(), reads);
+
+            b_peer.block_writes(false);
+            let fwd = fwd.ready(EventSet::writable(), scope)
+                .expect_machine();
+            assert_eq!(b_peer.take_output(), b"hello world");
+            assert_eq!(fwd.forwarded(), (11, 0));
+            assert_eq!(fwd.stats().1.bytes_written, 11);
+
+            b_peer.push(b"reply");
+            b_peer.push_eof();
+            let fwd = fwd.ready(EventSet::readable(), scope)
+                .expect_machine();
+            assert_eq!(a_peer.take_output(), b"reply");
+            assert!(a_peer.is_write_shutdown());
+            assert!(!b_peer.is_write_shutdown());
+            a_peer.push_eof();
+            fwd.ready(EventSet::readable(), scope).expect_done();
+            assert!(b_peer.is_write_shutdown());
+        });
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let (a, _a_peer) = pair();
+        let (b, _b_peer) = pair();
+        let options = StreamOptions::new()
+            .idle_timeout(Duration::from_millis(100));
+        let fwd = with_scope(&mut (), Time::zero(), |scope| {
+            Forward::with_options(a, options, b, StreamOptions::new(),
+                                  4, scope)
+                .expect_machine()
+        });
+        let later = Time::zero() + Duration::from_millis(200);
+        with_scope(&mut (), later, |scope| {
+            fwd.timeout(scope).expect_done();
+        });
+    }
+
+    #[cfg(target_os="linux")]
+    #[test]
+    fn splice() {
+        use std::io::{Read, Write};
+        use std::net::{TcpListener, TcpStream as StdStream, Shutdown};
+        use std::os::unix::io::{FromRawFd, IntoRawFd};
+        use rotor::mio::tcp::TcpStream;
+
+        fn socket_pair() -> (TcpStream, StdStream) {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let client = StdStream::connect(listener.local_addr().unwrap())
+                .unwrap();
+            client.set_nonblocking(true).unwrap();
+            let (server, _) = listener.accept().unwrap();
+            let sock = unsafe {
+                TcpStream::from_raw_fd(client.into_raw_fd())
+            };
+            (sock, server)
+        }
+
+        let (a, mut a_peer) = socket_pair();
+        let (b, mut b_peer) = socket_pair();
+        a_peer.write_all(b"hello").unwrap();
+        a_peer.shutdown(Shutdown::Write).unwrap();
+        let fwd = with_scope(&mut (), Time::zero(), |scope| {
+            let mut fwd = Forward::new(a, b, scope).expect_machine();
+            assert!(fwd.is_zero_copy());
+            while !fwd.a_to_b.shutdown {
+                fwd = fwd.ready(EventSet::readable(), scope)
+                    .expect_machine();
+            }
+            fwd
+        });
+        let mut buf = Vec::new();
+        b_peer.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf[..], b"hello");
+        assert_eq!(fwd.forwarded(), (5, 0));
+    }
+}
@@ -5,6 +5,26 @@ use rotor::Time;
 
 use {Expectation, Intent, IntentBuilder};
 
+
+/// Deadline of the `Intent`
+#[derive(Debug, Clone, Copy)]
+pub enum Deadline {
+    /// Replace the deadline, `None` means no deadline
+    Set(Option<Time>),
+    /// Keep the deadline of the previous intent
+    Keep,
+}
+
+impl Deadline {
+    /// Returns the deadline, given the deadline of the previous intent
+    pub fn resolve(self, previous: Option<Time>) -> Option<Time> {
+        match self {
+            Deadline::Set(x) => x,
+            Deadline::Keep => previous,
+        }
+    }
+}
+
 impl<M> Intent<M> {
     /// Start building the Intent object of the state machine
     pub fn of(machine: M) -> IntentBuilder<M> {
@@ -12,55 +32,69 @@ impl<M> Intent<M> {
     }
     /// Notifies that state machine has done it's work
     pub fn done() -> Self {
-        Intent(Err(None), Expectation::Sleep, None)
+        Intent(Err(None), Expectation::Sleep, Deadline::Set(None))
     }
     /// Notifies that we have protocol error and connection should be closed
     pub fn error(e: Box<Error>) -> Self {
-        Intent(Err(Some(e)), Expectation::Sleep, None)
+        Intent(Err(Some(e)), Expectation::Sleep, Deadline::Set(None))
     }
     /// Add/change the deadline
     ///
-    /// Note: if you skip this method on next return timeout will be reset.
-    /// Which means this state machine may hang indefinitely.
+    /// Note: if you skip this method on next return timeout will be reset
+    /// (unless `keep_deadline()` is used). Which means this state machine
+    /// may hang indefinitely.
     pub fn deadline(self, deadline: Time) -> Intent<M> {
-        Intent(self.0, self.1, Some(deadline))
+        Intent(self.0, self.1, Deadline::Set(Some(deadline)))
     }
     /// Add/change the deadline as an optional value
     ///
     /// Note this will reset timeout if deadline is None, not keep unchanged
     pub fn deadline_opt(self, deadline: Option<Time>) -> Intent<M> {
-        Intent(self.0, self.1, deadline)
+        Intent(self.0, self.1, Deadline::Set(deadline))
+    }
+    /// Keep the deadline of the previous intent unchanged
+    ///
+    /// This is useful to have a single deadline for the multiple steps of
+    /// the protocol, e.g. for the whole request. The active deadline is
+    /// returned by `Transport::deadline()`.
+    ///
+    /// Note: the deadline is already reached when `Protocol::timeout` is
+    /// called, so keeping it there makes timeout fire again.
+    pub fn keep_deadline(self) -> Intent<M> {
+        Intent(self.0, self.1, Deadline::Keep)
     }
 }
 
 impl<M> IntentBuilder<M> {
     pub fn expect_bytes(self, min_bytes: usize) -> Intent<M> {
-        Intent(Ok(self.0), Expectation::Bytes(min_bytes), None)
+        Intent(Ok(self.0), Expectation::Bytes(min_bytes), Deadline::Set(None))
     }
     pub fn expect_delimiter(self, delim: &'static [u8], max_bytes: usize)
         -> Intent<M>
     {
         Intent(Ok(self.0),
-               Expectation::Delimiter(0, delim, max_bytes), None)
+               Expectation::Delimiter(0, delim, max_bytes),
+               Deadline::Set(None))
     }
     pub fn expect_delimiter_after(self, offset: usize,
         delim: &'static [u8], max_bytes: usize)
         -> Intent<M>
     {
         Intent(Ok(self.0),
-               Expectation::Delimiter(offset, delim, max_bytes), None)
+               Expectation::Delimiter(offset, delim, max_bytes),
+               Deadline::Set(None))
     }
     pub fn expect_flush(self) -> Intent<M> {
-        Intent(Ok(self.0), Expectation::Flush(0), None)
+        Intent(Ok(self.0), Expectation::Flush(0), Deadline::Set(None))
     }
     /// Add a generic expectation
     ///
     /// The method is useful if you're returning an expectation from somewhere
     /// otherwise use specific `expect_*` methods
     pub fn expect(self, e: Expectation) -> Intent<M> {
-        Intent(Ok(self.0), e, None)
+        Intent(Ok(self.0), e, Deadline::Set(None))
     }
     pub fn sleep(self) -> Intent<M> {
-        Intent(Ok(self.0), Expectation::Sleep, None)
+        Intent(Ok(self.0), Expectation::Sleep, Deadline::Set(None))
     }
 }
@@ -25,6 +25,7 @@
 extern crate netbuf;
 extern crate memchr;
 extern crate rotor;
+#[cfg(unix)] extern crate libc;
 #[macro_use] extern crate log;
 #[macro_use] extern crate quick_error;
 #[cfg(feature="replaceable")] extern crate rotor_tools;
@@ -40,17 +41,42 @@ mod trait_impls;
 mod intention;
 mod extensions;
 mod errors;
+mod drain;
+mod listener;
+#[cfg(unix)] mod activation;
+mod proxy_protocol;
+mod recording;
+mod stats;
+mod metrics;
+mod output;
+mod forward;
+mod proxy;
+mod rate;
+pub mod testing;
 
 pub use protocol::{Protocol, Expectation, Exception};
-pub use accept::{Accepted};
+pub use accept::{Accepted, ServerState, ConnectionSlot};
+pub use listener::{Listener, ListenerSet, AnySocket};
+pub use proxy_protocol::{ProxyProtocolStream, ProxyHeader, parse_header};
+pub use proxy_protocol::{PROXY_HEADER_TIMEOUT};
+#[cfg(unix)] pub use activation::{systemd_listeners, LISTEN_FDS_START};
+#[cfg(unix)]
+pub use activation::{listeners_from_raw_fds, listener_from_raw_fd};
 pub use persistent::{Persistent};
-pub use errors::ProtocolStop;
+pub use errors::{ProtocolStop, InvalidProxyHeader};
+pub use metrics::{Metrics, set_metrics};
+pub use forward::{Forward, FORWARD_BUFFER};
+pub use proxy::{Proxy, ProxyConfig, Inspection, INSPECT_TIMEOUT};
+pub use proxy::{UPSTREAM_CONNECT_TIMEOUT};
 #[cfg(feature="replaceable")] pub use rotor_tools::sync;
 
 use std::any::Any;
 use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::io::{Read, Write};
 use std::error::Error;
+#[cfg(unix)] use std::os::unix::io::RawFd;
 
 use rotor::{Evented, Time};
 use rotor::mio::{TryAccept};
@@ -71,6 +97,11 @@ pub struct Transport<'a, S: StreamSocket> {
     sock: &'a mut S,
     inbuf: &'a mut Buf,
     outbuf: &'a mut Buf,
+    outqueue: &'a mut output::OutputQueue,
+    proxy: Option<&'a ProxyHeader>,
+    stats: &'a Stats,
+    options: &'a StreamOptions,
+    deadline: Option<Time>,
 }
 
 /// Socket acceptor State Machine
@@ -81,10 +112,41 @@ pub enum Accept<M, A: TryAccept+Sized>
     where A::Output: StreamSocket,
           M: Accepted<Socket=A::Output>,
 {
-    Server(A, <M as Accepted>::Seed),
-    Connection(M),
+    Server(A, <M as Accepted>::Seed, ServerState),
+    Connection(M, ConnectionSlot),
+}
+
+/// Options of the listening socket of `Accept` state machine
+///
+/// Use `Accept::with_options` to create acceptor with these options
+#[derive(Debug, Clone)]
+pub struct AcceptOptions {
+    drain: Option<Drain>,
+    batch_limit: usize,
+    limit: Option<Arc<accept::ConnectionLimit>>,
+    // key of the acceptor in `ConnectionLimit`, set for each acceptor
+    limit_key: Option<usize>,
+    stream: StreamOptions,
 }
 
+/// A handle used to gracefully shut down the server
+///
+/// Create it with `Drain::new()` and pass it to `Accept::with_drain`. When
+/// `start()` is called all the `Accept` servers registered with the handle
+/// are woken up, they close their listening sockets and stop accepting
+/// connections. Connections accepted earlier are left intact, and when the
+/// last of them is closed the event loop is shut down.
+///
+/// Protocols of the accepted connections may check
+/// `Transport::is_draining()`, for example to send `Connection: close` in
+/// HTTP when the next request arrives. They are not woken up when draining
+/// starts, so idle connections should be limited by timeouts.
+///
+/// The handle is meant to be used for a single loop. Use separate handles
+/// if you run multiple loops in different threads.
+#[derive(Clone)]
+pub struct Drain(Arc<drain::DrainState>);
+
 /// A main stream state machine abstaction
 ///
 /// You may use the `Stream` directly. But it's recommented to either use
@@ -96,25 +158,139 @@ pub struct Stream<P: Protocol> {
     fsm: P,
     expectation: Expectation,
     connected: bool,
+    readable: bool,
+    writable: bool,
+    yielded: bool,
+    read_limit: Option<rate::RateLimit>,
+    write_limit: Option<rate::RateLimit>,
+    resume: Option<Time>,
+    idle: Option<Time>,
     deadline: Option<Time>,
     inbuf: Buf,
     outbuf: Buf,
+    outqueue: output::OutputQueue,
+    proxy: Option<Box<ProxyHeader>>,
+    stats: Stats,
+    options: StreamOptions,
 }
 
 struct StreamImpl<S: StreamSocket> {
     socket: S,
     connected: bool,
+    // False when the last read (write) returned `WouldBlock` and there was
+    // no readable (writable) event since then. Socket is registered in
+    // edge-triggered mode, so there is no sense to try again.
+    readable: bool,
+    writable: bool,
+    // True if processing was interrupted because of the read budget, and
+    // the wakeup is scheduled to continue it
+    yielded: bool,
+    read_limit: Option<rate::RateLimit>,
+    write_limit: Option<rate::RateLimit>,
+    // Time when I/O paused by rate limits can continue
+    resume: Option<Time>,
+    // Time when the idle timeout expires
+    idle: Option<Time>,
+    deadline: Option<Time>,
     inbuf: Buf,
     outbuf: Buf,
+    outqueue: output::OutputQueue,
+    proxy: Option<Box<ProxyHeader>>,
+    stats: Stats,
+    options: StreamOptions,
+}
+
+/// Options of the `Stream`
+///
+/// Use `Stream::with_options` to create a stream with these options, or
+/// `AcceptOptions::stream_options` to apply them to accepted connections.
+///
+/// Note there is no option to pool buffers between connections yet. The
+/// `netbuf::Buf` frees its memory whenever it becomes empty (on `consume`,
+/// `remove_range(..)` and on `read_from` returning zero bytes) and can't
+/// be created from an existing allocation, so an empty buffer returned to
+/// a pool would hold no memory to reuse. The pool is postponed until
+/// netbuf can keep and adopt allocations. Until then the allocation churn
+/// is per message rather than per connection: a buffer is allocated when
+/// data arrives (at least 16KiB for reads) and freed when it's consumed.
+/// See `shrink_buffers` to limit the memory kept by idle connections.
+#[derive(Debug, Clone)]
+pub struct StreamOptions {
+    max_input: usize,
+    output_high_water: usize,
+    max_output: usize,
+    shrink_threshold: Option<usize>,
+    read_budget: usize,
+    callback_budget: usize,
+    read_rate: Option<u64>,
+    write_rate: Option<u64>,
+    idle_timeout: Option<Duration>,
+    drain: Option<Drain>,
+    proxy_header_timeout: Duration,
+    recording: Option<testing::Recording>,
+}
+
+/// I/O statistics of the connection
+///
+/// Use `Transport::stats()` to get statistics of the current connection,
+/// and `Persistent::stats()` to get the sum for all connections made by
+/// the persistent client.
+#[derive(Debug, Clone)]
+pub struct Stats {
+    /// Number of bytes read from the socket
+    pub bytes_read: u64,
+    /// Number of bytes written to the socket
+    pub bytes_written: u64,
+    /// Number of `read()` system calls
+    pub read_calls: u64,
+    /// Number of `write()` system calls
+    pub write_calls: u64,
+    /// Number of `read()` calls that returned `WouldBlock`
+    pub read_would_block: u64,
+    /// Number of `write()` calls that returned `WouldBlock`
+    pub write_would_block: u64,
+    /// Maximum number of bytes in the input buffer
+    pub max_inbuf: usize,
+    /// Maximum number of bytes in the output buffer
+    pub max_outbuf: usize,
+    connected_since: Option<Instant>,
+    connected_before: Duration,
 }
 
 pub trait ActiveStream: StreamSocket {
     type Address;
     fn connect(addr: &Self::Address) -> io::Result<Self>;
+    /// Returns true if the connection started by `connect()` is established
+    ///
+    /// This is used by `Proxy` to apply the connect timeout. Default
+    /// implementation returns true, i.e. the connection is assumed to be
+    /// established immediately.
+    fn is_connected(&self) -> bool {
+        true
+    }
 }
 
 pub trait SocketError {
     fn take_socket_error(&self) -> io::Result<()>;
+    /// Shut down the writing side of the socket
+    ///
+    /// This is used to propagate half-close, e.g. by `Forward`. Default
+    /// implementation returns an error, as the peer would never see the
+    /// end of stream otherwise.
+    fn shutdown_write(&self) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other,
+            "shutdown is not supported by the socket"))
+    }
+    /// File descriptor to use for zero-copy transfers (`sendfile`, `writev`)
+    ///
+    /// Only return the descriptor if writing to it directly is equivalent
+    /// to `write()`ing to the socket. Wrappers which inspect or alter the
+    /// data must return `None` (the default), in which case the data is
+    /// copied through user space.
+    #[cfg(unix)]
+    fn zero_copy_fd(&self) -> Option<RawFd> {
+        None
+    }
 }
 
 /// A structure that encapsulates a state machine and an expectation
@@ -135,7 +311,8 @@ pub trait SocketError {
 /// ```
 ///
 #[derive(Debug)]
-pub struct Intent<M>(Result<M, Option<Box<Error>>>, Expectation, Option<Time>);
+pub struct Intent<M>(Result<M, Option<Box<Error>>>, Expectation,
+                     intention::Deadline);
 
 /// A helper class returned from `Intent::of()`
 ///
@@ -0,0 +1,326 @@
+use std::io;
+use std::io::{Read, Write};
+use std::cell::Cell;
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+
+use rotor::mio::{Evented, EventSet, PollOpt, Selector, Token, TryAccept};
+use rotor::mio::tcp::{TcpListener, TcpStream};
+#[cfg(unix)]
+use rotor::mio::unix::{UnixListener, UnixStream};
+
+use {SocketError};
+
+
+/// A listening socket of any supported kind
+///
+/// This is mostly used to put listeners of different kinds into a single
+/// `ListenerSet`
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// A stream socket accepted from the `Listener`
+#[derive(Debug)]
+pub enum AnySocket {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+/// A set of listening sockets which share single `Accept` state machine
+///
+/// This allows to listen on multiple ports and unix sockets with the same
+/// protocol, seed and options (including connection limit):
+///
+/// ```ignore
+/// let listeners = ListenerSet::new(vec![
+///     TcpListener::bind(&"0.0.0.0:80".parse().unwrap()).unwrap().into(),
+///     TcpListener::bind(&"[::]:80".parse().unwrap()).unwrap().into(),
+///     UnixListener::bind("/run/app.sock").unwrap().into(),
+/// ]);
+/// Accept::<Stream<Http>, _>::new(listeners, seed, scope)
+/// ```
+///
+/// The protocol should use `AnySocket` as a socket type in this case.
+#[derive(Debug)]
+pub struct ListenerSet {
+    listeners: Vec<Listener>,
+    // index of the listener to start accepting from, this is needed for
+    // fairness between listeners
+    next: Cell<usize>,
+}
+
+impl ListenerSet {
+    pub fn new(listeners: Vec<Listener>) -> ListenerSet {
+        ListenerSet {
+            listeners: listeners,
+            next: Cell::new(0),
+        }
+    }
+    /// Returns the listeners of the set
+    pub fn listeners(&self) -> &[Listener] {
+        &self.listeners
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(lst: TcpListener) -> Listener {
+        Listener::Tcp(lst)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixListener> for Listener {
+    fn from(lst: UnixListener) -> Listener {
+        Listener::Unix(lst)
+    }
+}
+
+impl Evented for Listener {
+    fn register(&self, selector: &mut Selector, token: Token,
+        interest: EventSet, opts: PollOpt)
+        -> io::Result<()>
+    {
+        match *self {
+            Listener::Tcp(ref l) => {
+                l.register(selector, token, interest, opts)
+            }
+            #[cfg(unix)]
+            Listener::Unix(ref l) => {
+                l.register(selector, token, interest, opts)
+            }
+        }
+    }
+    fn reregister(&self, selector: &mut Selector, token: Token,
+        interest: EventSet, opts: PollOpt)
+        -> io::Result<()>
+    {
+        match *self {
+            Listener::Tcp(ref l) => {
+                l.reregister(selector, token, interest, opts)
+            }
+            #[cfg(unix)]
+            Listener::Unix(ref l) => {
+                l.reregister(selector, token, interest, opts)
+            }
+        }
+    }
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        match *self {
+            Listener::Tcp(ref l) => l.deregister(selector),
+            #[cfg(unix)]
+            Listener::Unix(ref l) => l.deregister(selector),
+        }
+    }
+}
+
+impl TryAccept for Listener {
+    type Output = AnySocket;
+    fn accept(&self) -> io::Result<Option<AnySocket>> {
+        match *self {
+            Listener::Tcp(ref l) => {
+                TryAccept::accept(l).map(|x| x.map(AnySocket::Tcp))
+            }
+            #[cfg(unix)]
+            Listener::Unix(ref l) => {
+                TryAccept::accept(l).map(|x| x.map(AnySocket::Unix))
+            }
+        }
+    }
+}
+
+impl Evented for ListenerSet {
+    fn register(&self, selector: &mut Selector, token: Token,
+        interest: EventSet, opts: PollOpt)
+        -> io::Result<()>
+    {
+        for lst in &self.listeners {
+            try!(lst.register(selector, token, interest, opts));
+        }
+        Ok(())
+    }
+    fn reregister(&self, selector: &mut Selector, token: Token,
+        interest: EventSet, opts: PollOpt)
+        -> io::Result<()>
+    {
+        for lst in &self.listeners {
+            try!(lst.reregister(selector, token, interest, opts));
+        }
+        Ok(())
+    }
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        for lst in &self.listeners {
+            try!(lst.deregister(selector));
+        }
+        Ok(())
+    }
+}
+
+impl TryAccept for ListenerSet {
+    type Output = AnySocket;
+    /// Accepts a connection from any of the listeners
+    ///
+    /// Returns `Ok(None)` only if there is nothing to accept on all the
+    /// listeners. The error is returned only if no other listener has
+    /// a connection to accept, so broken listener doesn't block others.
+    fn accept(&self) -> io::Result<Option<AnySocket>> {
+        let num = self.listeners.len();
+        let start = self.next.get();
+        let mut error = None;
+        for i in 0..num {
+            let idx = (start + i) % num;
+            match self.listeners[idx].accept() {
+                Ok(Some(sock)) => {
+                    self.next.set((idx + 1) % num);
+                    return Ok(Some(sock));
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    if error.is_none() {
+                        error = Some(e);
+                    }
+                }
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Read for AnySocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            AnySocket::Tcp(ref mut s) => s.read(buf),
+            #[cfg(unix)]
+            AnySocket::Unix(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for AnySocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            AnySocket::Tcp(ref mut s) => s.write(buf),
+            #[cfg(unix)]
+            AnySocket::Unix(ref mut s) => s.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            AnySocket::Tcp(ref mut s) => s.flush(),
+            #[cfg(unix)]
+            AnySocket::Unix(ref mut s) => s.flush(),
+        }
+    }
+}
+
+impl Evented for AnySocket {
+    fn register(&self, selector: &mut Selector, token: Token,
+        interest: EventSet, opts: PollOpt)
+        -> io::Result<()>
+    {
+        match *self {
+            AnySocket::Tcp(ref s) => {
+                s.register(selector, token, interest, opts)
+            }
+            #[cfg(unix)]
+            AnySocket::Unix(ref s) => {
+                s.register(selector, token, interest, opts)
+            }
+        }
+    }
+    fn reregister(&self, selector: &mut Selector, token: Token,
+        interest: EventSet, opts: PollOpt)
+        -> io::Result<()>
+    {
+        match *self {
+            AnySocket::Tcp(ref s) => {
+                s.reregister(selector, token, interest, opts)
+            }
+            #[cfg(unix)]
+            AnySocket::Unix(ref s) => {
+                s.reregister(selector, token, interest, opts)
+            }
+        }
+    }
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        match *self {
+            AnySocket::Tcp(ref s) => s.deregister(selector),
+            #[cfg(unix)]
+            AnySocket::Unix(ref s) => s.deregister(selector),
+        }
+    }
+}
+
+impl SocketError for AnySocket {
+    fn take_socket_error(&self) -> io::Result<()> {
+        match *self {
+            AnySocket::Tcp(ref s) => SocketError::take_socket_error(s),
+            #[cfg(unix)]
+            AnySocket::Unix(ref s) => SocketError::take_socket_error(s),
+        }
+    }
+    fn shutdown_write(&self) -> io::Result<()> {
+        match *self {
+            AnySocket::Tcp(ref s) => SocketError::shutdown_write(s),
+            #[cfg(unix)]
+            AnySocket::Unix(ref s) => SocketError::shutdown_write(s),
+        }
+    }
+    #[cfg(unix)]
+    fn zero_copy_fd(&self) -> Option<RawFd> {
+        match *self {
+            AnySocket::Tcp(ref s) => SocketError::zero_copy_fd(s),
+            AnySocket::Unix(ref s) => SocketError::zero_copy_fd(s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpStream as StdStream;
+
+    use rotor::mio::TryAccept;
+    use rotor::mio::tcp::TcpListener;
+    use super::{Listener, ListenerSet, AnySocket};
+
+    fn port(set: &ListenerSet, sock: &AnySocket) -> usize {
+        let addr = match *sock {
+            AnySocket::Tcp(ref s) => s.local_addr().unwrap(),
+            #[cfg(unix)]
+            AnySocket::Unix(_) => unreachable!(),
+        };
+        set.listeners().iter().position(|l| match *l {
+            Listener::Tcp(ref l) => l.local_addr().unwrap() == addr,
+            #[cfg(unix)]
+            Listener::Unix(_) => false,
+        }).unwrap()
+    }
+
+    #[test]
+    fn several_listeners() {
+        let local = "127.0.0.1:0".parse().unwrap();
+        let l1 = TcpListener::bind(&local).unwrap();
+        let l2 = TcpListener::bind(&local).unwrap();
+        let a1 = l1.local_addr().unwrap();
+        let a2 = l2.local_addr().unwrap();
+        let set = ListenerSet::new(vec![l1.into(), l2.into()]);
+        let _clients = vec![
+            StdStream::connect(a1).unwrap(),
+            StdStream::connect(a1).unwrap(),
+            StdStream::connect(a2).unwrap(),
+        ];
+        let mut order = Vec::new();
+        while let Some(sock) = set.accept().unwrap() {
+            order.push(port(&set, &sock));
+        }
+        // Listeners are taken in turn, so the second one isn't starved
+        assert_eq!(order, vec![0, 1, 0]);
+    }
+}
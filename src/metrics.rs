@@ -0,0 +1,67 @@
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use {Exception, Stats};
+
+
+// Pointer to the `Box<Metrics>`, zero when metrics are not set
+static METRICS: AtomicUsize = AtomicUsize::new(0);
+
+
+/// A receiver of events for metrics collection
+///
+/// All methods are no-op by default, so you may implement only those you
+/// are interested in. Install the implementation with `set_metrics()`.
+///
+/// The methods are called synchronously from the event loop, in every
+/// thread running a loop, so they should be fast (e.g. increment an atomic
+/// counter).
+pub trait Metrics: Send + Sync {
+    /// A connection is accepted by the `Accept` state machine
+    fn connection_accepted(&self) {}
+    /// An error occured when accepting a connection
+    fn accept_error(&self, _err: &io::Error) {}
+    /// A connection made by `Persistent` or `Proxy` is established
+    fn connect_success(&self) {}
+    /// A connection made by `Persistent` or `Proxy` failed
+    fn connect_failure(&self) {}
+    /// A connection made by `Persistent` or `Proxy` is not established
+    /// in time
+    fn connect_timeout(&self) {}
+    /// The `Stream` is closed (either by protocol or because of error)
+    fn connection_closed(&self, _stats: &Stats) {}
+    /// The `Protocol::exception` is going to be called
+    fn exception(&self, _reason: &Exception) {}
+    /// The `Protocol::fatal` is going to be called
+    fn fatal(&self, _reason: &Exception) {}
+    /// A number of bytes is read from the `Stream` socket
+    fn bytes_read(&self, _bytes: usize) {}
+    /// A number of bytes is written to the `Stream` socket
+    fn bytes_written(&self, _bytes: usize) {}
+}
+
+/// Install the global metrics receiver
+///
+/// This may be called only once. If metrics are already set, the
+/// `metrics` object is returned back as an error.
+///
+/// When metrics are not set, the only overhead is an atomic load for every
+/// event.
+pub fn set_metrics(metrics: Box<Metrics>) -> Result<(), Box<Metrics>> {
+    let ptr = Box::into_raw(Box::new(metrics));
+    match METRICS.compare_exchange(0, ptr as usize,
+        Ordering::SeqCst, Ordering::SeqCst)
+    {
+        Ok(_) => Ok(()),
+        Err(_) => Err(*unsafe { Box::from_raw(ptr) }),
+    }
+}
+
+/// Call the function with the installed metrics receiver (if any)
+pub fn report<F: FnOnce(&Metrics)>(f: F) {
+    let ptr = METRICS.load(Ordering::Acquire);
+    if ptr != 0 {
+        // The box is never freed after being installed
+        f(&**unsafe { &*(ptr as *const Box<Metrics>) })
+    }
+}
@@ -0,0 +1,459 @@
+use std::io;
+use std::mem;
+use std::cmp::min;
+use std::fs::File;
+use std::sync::Arc;
+use std::collections::VecDeque;
+use std::io::{Read, Seek, SeekFrom};
+use std::io::ErrorKind::UnexpectedEof;
+#[cfg(unix)] use std::os::unix::io::RawFd;
+
+use {Buf, StreamSocket};
+
+
+// Size of the chunk used to copy the file when `sendfile` is not supported
+const COPY_CHUNK: usize = 16384;
+// Linux never transfers more than this by a single `sendfile`
+#[cfg(target_os="linux")]
+const MAX_SENDFILE: u64 = 0x7ffff000;
+// Maximum number of slices written by single `writev`, must be less than
+// IOV_MAX, which is 1024 on linux
+const MAX_SLICES: usize = 64;
+
+
+/// A piece of output which is not in the output buffer
+#[derive(Debug)]
+enum Chunk {
+    /// Output buffer contents, written before the entries queued after it
+    Buffer(Buf),
+    /// Shared data and the number of bytes already written
+    Shared(Arc<Vec<u8>>, usize),
+    /// File, offset and number of bytes left
+    File(File, u64, u64),
+}
+
+impl Chunk {
+    // Returns the bytes to write, or `None` if the chunk is not in memory
+    fn bytes(&self) -> Option<&[u8]> {
+        match *self {
+            Chunk::Buffer(ref buf) => Some(&buf[..]),
+            Chunk::Shared(ref data, pos) => Some(&data[pos..]),
+            Chunk::File(..) => None,
+        }
+    }
+}
+
+/// The entries which must be written before the output buffer
+///
+/// When an entry is queued the output buffer contents is moved into the
+/// queue first, so the order of writes is preserved. In the common case
+/// nothing is queued and the output buffer is written directly.
+///
+/// Chunks in memory (including the output buffer after them) are written
+/// by a single `writev` if socket supports it.
+#[derive(Debug)]
+pub struct OutputQueue {
+    chunks: VecDeque<Chunk>,
+    // Running totals, as these are checked after every callback
+    len: u64,
+    buffered: usize,
+}
+
+impl OutputQueue {
+    pub fn new() -> OutputQueue {
+        OutputQueue {
+            chunks: VecDeque::new(),
+            len: 0,
+            buffered: 0,
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+    /// Number of bytes in the queue
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+    /// Number of bytes of the queue stored in memory
+    pub fn buffered(&self) -> usize {
+        self.buffered
+    }
+    fn take_buffer(&mut self, outbuf: &mut Buf) {
+        if outbuf.len() > 0 {
+            self.len += outbuf.len() as u64;
+            self.buffered += outbuf.len();
+            self.chunks.push_back(
+                Chunk::Buffer(mem::replace(outbuf, Buf::new())));
+        }
+    }
+    pub fn push_shared(&mut self, outbuf: &mut Buf, data: Arc<Vec<u8>>) {
+        if data.len() > 0 {
+            self.take_buffer(outbuf);
+            self.len += data.len() as u64;
+            self.buffered += data.len();
+            self.chunks.push_back(Chunk::Shared(data, 0));
+        }
+    }
+    pub fn push_file(&mut self, outbuf: &mut Buf,
+        file: File, offset: u64, len: u64)
+    {
+        if len > 0 {
+            self.take_buffer(outbuf);
+            self.len += len;
+            self.chunks.push_back(Chunk::File(file, offset, len));
+        }
+    }
+    /// Writes the first entry of the queue
+    ///
+    /// Returns `None` if queue is empty. Otherwise the result has same
+    /// meaning as the one of `Write::write`, except the file which is
+    /// shorter than expected is reported as an `UnexpectedEof` error.
+    ///
+    /// The `outbuf` may be written too if it's after the chunks in memory.
+    /// No more than `max` bytes are written.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is zero, as zero bytes written means end of stream
+    pub fn write_to<S: StreamSocket>(&mut self, outbuf: &mut Buf,
+        sock: &mut S, max: usize)
+        -> Option<io::Result<usize>>
+    {
+        assert!(max > 0, "OutputQueue::write_to called with zero max");
+        match self.chunks.front().map(|c| c.bytes().is_some()) {
+            None => None,
+            Some(true) => Some(self.write_memory(outbuf, sock, max)),
+            Some(false) => Some(self.write_file(sock, max)),
+        }
+    }
+    fn write_file<S: StreamSocket>(&mut self, sock: &mut S, max: usize)
+        -> io::Result<usize>
+    {
+        let (res, done) = match self.chunks.front_mut() {
+            Some(&mut Chunk::File(ref file, ref mut offset, ref mut len)) => {
+                let res = send_file(sock, file, *offset,
+                                    min(*len, max as u64));
+                if let Ok(bytes) = res {
+                    *offset += bytes as u64;
+                    *len -= bytes as u64;
+                    self.len -= bytes as u64;
+                }
+                (res, *len == 0)
+            }
+            _ => unreachable!(),
+        };
+        if done {
+            self.chunks.pop_front();
+        }
+        res
+    }
+    fn write_memory<S: StreamSocket>(&mut self, outbuf: &mut Buf,
+        sock: &mut S, max: usize)
+        -> io::Result<usize>
+    {
+        let bytes = try!(self.write_slices(outbuf, sock, max));
+        self.consume(outbuf, bytes);
+        Ok(bytes)
+    }
+    // Consecutive chunks in memory at the start of the queue, up to `max`
+    // bytes total
+    fn slices<'x>(&'x self, outbuf: &'x Buf, mut max: usize)
+        -> Vec<&'x [u8]>
+    {
+        let mut slices = Vec::new();
+        let chunks = self.chunks.iter().map(|c| c.bytes())
+            .chain(Some(Some(&outbuf[..])));
+        for bytes in chunks {
+            let bytes = match bytes {
+                Some(bytes) => &bytes[..min(bytes.len(), max)],
+                None => break,
+            };
+            if bytes.len() > 0 {
+                slices.push(bytes);
+            }
+            max -= bytes.len();
+            if max == 0 || slices.len() >= MAX_SLICES {
+                break;
+            }
+        }
+        slices
+    }
+    #[cfg(unix)]
+    fn write_slices<S: StreamSocket>(&self, outbuf: &Buf, sock: &mut S,
+        max: usize)
+        -> io::Result<usize>
+    {
+        let slices = self.slices(outbuf, max);
+        match sock.zero_copy_fd() {
+            Some(fd) if slices.len() > 1 => writev(fd, &slices),
+            _ => sock.write(slices[0]),
+        }
+    }
+    #[cfg(not(unix))]
+    fn write_slices<S: StreamSocket>(&self, outbuf: &Buf, sock: &mut S,
+        max: usize)
+        -> io::Result<usize>
+    {
+        sock.write(self.slices(outbuf, max)[0])
+    }
+    // Removes `bytes` written by `write_slices`
+    fn consume(&mut self, outbuf: &mut Buf, mut bytes: usize) {
+        while bytes > 0 {
+            let (num, done) = match self.chunks.front_mut() {
+                None => {
+                    outbuf.consume(bytes);
+                    return;
+                }
+                Some(&mut Chunk::Buffer(ref mut buf)) => {
+                    let num = min(bytes, buf.len());
+                    buf.consume(num);
+                    (num, buf.len() == 0)
+                }
+                Some(&mut Chunk::Shared(ref data, ref mut pos)) => {
+                    let num = min(bytes, data.len() - *pos);
+                    *pos += num;
+                    (num, *pos == data.len())
+                }
+                Some(&mut Chunk::File(..)) => unreachable!(),
+            };
+            bytes -= num;
+            self.len -= num as u64;
+            self.buffered -= num;
+            if done {
+                self.chunks.pop_front();
+            }
+        }
+    }
+}
+
+fn send_file<S: StreamSocket>(sock: &mut S, file: &File,
+    offset: u64, len: u64)
+    -> io::Result<usize>
+{
+    #[cfg(target_os="linux")]
+    {
+        use libc::{EINVAL, ENOSYS};
+
+        if let Some(fd) = sock.zero_copy_fd() {
+            match sendfile(fd, file, offset, len) {
+                // The file (e.g. in procfs) doesn't support `sendfile`
+                Err(ref e) if e.raw_os_error() == Some(EINVAL) ||
+                              e.raw_os_error() == Some(ENOSYS) => {}
+                res => return res,
+            }
+        }
+    }
+    let mut buf = [0u8; COPY_CHUNK];
+    let max = min(len, COPY_CHUNK as u64) as usize;
+    let mut file = file;
+    try!(file.seek(SeekFrom::Start(offset)));
+    let bytes = try!(file.read(&mut buf[..max]));
+    if bytes == 0 {
+        return Err(file_truncated());
+    }
+    // If write is short, the rest is read from the file again next time
+    sock.write(&buf[..bytes])
+}
+
+#[cfg(target_os="linux")]
+fn sendfile(fd: RawFd, file: &File,
+    offset: u64, len: u64)
+    -> io::Result<usize>
+{
+    use std::os::unix::io::AsRawFd;
+    use libc;
+
+    let mut off = offset as libc::off_t;
+    let count = min(len, MAX_SENDFILE) as usize;
+    let res = unsafe {
+        libc::sendfile(fd, file.as_raw_fd(), &mut off, count)
+    };
+    match res {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Err(file_truncated()),
+        bytes => Ok(bytes as usize),
+    }
+}
+
+#[cfg(unix)]
+fn writev(fd: RawFd, slices: &[&[u8]]) -> io::Result<usize> {
+    use libc;
+
+    let iovecs = slices.iter().map(|s| libc::iovec {
+        iov_base: s.as_ptr() as *mut libc::c_void,
+        iov_len: s.len(),
+    }).collect::<Vec<_>>();
+    let res = unsafe {
+        libc::writev(fd, iovecs.as_ptr(), iovecs.len() as libc::c_int)
+    };
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res as usize)
+    }
+}
+
+fn file_truncated() -> io::Error {
+    io::Error::new(UnexpectedEof, "file is shorter than expected")
+}
+
+#[cfg(all(test, target_os="linux"))]
+mod test {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream as StdStream};
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    use std::os::unix::net::UnixStream as StdUnixStream;
+    use std::process;
+    use std::sync::Arc;
+    use std::usize;
+
+    use rotor::mio::tcp::TcpStream;
+    use rotor::mio::unix::UnixStream;
+    use testing::{pair, WriteEvent};
+    use super::OutputQueue;
+    use {Buf};
+
+    fn socket_pair() -> (TcpStream, StdStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = StdStream::connect(listener.local_addr().unwrap())
+            .unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let sock = unsafe { TcpStream::from_raw_fd(client.into_raw_fd()) };
+        (sock, server)
+    }
+
+    #[test]
+    fn sendfile() {
+        let path = env::temp_dir().join(
+            format!("rotor-stream-sendfile-{}", process::id()));
+        File::create(&path).unwrap().write_all(b"0123456789").unwrap();
+        let file = File::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let (mut sock, mut server) = socket_pair();
+        let mut queue = OutputQueue::new();
+        let mut buf = Buf::new();
+        buf.extend(b"head ");
+        queue.push_file(&mut buf, file, 2, 5);
+        assert_eq!(buf.len(), 0);
+        assert_eq!(queue.len(), 10);
+        assert_eq!(queue.buffered(), 5);
+        while let Some(res) = queue.write_to(&mut buf, &mut sock, usize::MAX)
+        {
+            res.unwrap();
+        }
+        assert_eq!(queue.len(), 0);
+        drop(sock);
+        let mut data = Vec::new();
+        server.read_to_end(&mut data).unwrap();
+        assert_eq!(&data[..], b"head 23456");
+    }
+
+    #[test]
+    fn sendfile_fallback() {
+        // Procfs doesn't support `sendfile` for this file
+        let expected = fs::read("/proc/self/cmdline").unwrap();
+        let file = File::open("/proc/self/cmdline").unwrap();
+        let (mut sock, mut server) = socket_pair();
+        let mut queue = OutputQueue::new();
+        let mut buf = Buf::new();
+        queue.push_file(&mut buf, file, 0, expected.len() as u64);
+        while let Some(res) = queue.write_to(&mut buf, &mut sock, usize::MAX)
+        {
+            res.unwrap();
+        }
+        drop(sock);
+        let mut data = Vec::new();
+        server.read_to_end(&mut data).unwrap();
+        assert_eq!(data, expected);
+    }
+
+    #[test]
+    fn sendfile_unix() {
+        let path = env::temp_dir().join(
+            format!("rotor-stream-sendfile-unix-{}", process::id()));
+        File::create(&path).unwrap().write_all(b"0123456789").unwrap();
+        let file = File::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let (client, mut server) = StdUnixStream::pair().unwrap();
+        let mut sock = unsafe {
+            UnixStream::from_raw_fd(client.into_raw_fd())
+        };
+        let mut queue = OutputQueue::new();
+        let mut buf = Buf::new();
+        queue.push_file(&mut buf, file, 2, 5);
+        while let Some(res) = queue.write_to(&mut buf, &mut sock, usize::MAX)
+        {
+            res.unwrap();
+        }
+        drop(sock);
+        let mut data = Vec::new();
+        server.read_to_end(&mut data).unwrap();
+        assert_eq!(&data[..], b"23456");
+    }
+
+    #[test]
+    fn writev() {
+        let (mut sock, mut server) = socket_pair();
+        let data = Arc::new(b"shared ".to_vec());
+        let mut queue = OutputQueue::new();
+        let mut buf = Buf::new();
+        buf.extend(b"head ");
+        queue.push_shared(&mut buf, data.clone());
+        buf.extend(b"tail");
+        assert_eq!(queue.len(), 12);
+        assert_eq!(queue.buffered(), 12);
+        // All three slices are written by a single system call
+        let res = queue.write_to(&mut buf, &mut sock, usize::MAX);
+        assert_eq!(res.unwrap().unwrap(), 16);
+        assert_eq!(queue.len(), 0);
+        assert_eq!(buf.len(), 0);
+        assert!(queue.write_to(&mut buf, &mut sock, usize::MAX).is_none());
+        assert_eq!(Arc::strong_count(&data), 1);
+        drop(sock);
+        let mut result = Vec::new();
+        server.read_to_end(&mut result).unwrap();
+        assert_eq!(&result[..], b"head shared tail");
+    }
+
+    #[test]
+    fn totals() {
+        let (mut sock, peer) = pair();
+        peer.push_write_event(WriteEvent::Accept(3));
+        peer.push_write_event(WriteEvent::Accept(5));
+        let mut queue = OutputQueue::new();
+        let mut buf = Buf::new();
+        buf.extend(b"head ");
+        queue.push_shared(&mut buf, Arc::new(b"shared ".to_vec()));
+        buf.extend(b"tail");
+        assert_eq!((queue.len(), queue.buffered()), (12, 12));
+        let res = queue.write_to(&mut buf, &mut sock, usize::MAX);
+        assert_eq!(res.unwrap().unwrap(), 3);
+        assert_eq!((queue.len(), queue.buffered()), (9, 9));
+        // Without `writev` a single slice is written at a time
+        let res = queue.write_to(&mut buf, &mut sock, usize::MAX);
+        assert_eq!(res.unwrap().unwrap(), 2);
+        assert_eq!((queue.len(), queue.buffered()), (7, 7));
+        while let Some(res) = queue.write_to(&mut buf, &mut sock, usize::MAX)
+        {
+            res.unwrap();
+        }
+        assert_eq!((queue.len(), queue.buffered()), (0, 0));
+        assert_eq!(peer.output(), b"head shared ");
+        // The rest of the buffer is written by the stream itself
+        assert_eq!(&buf[..], b"tail");
+    }
+
+    #[test]
+    #[should_panic(expected="zero max")]
+    fn zero_max() {
+        let (mut sock, _peer) = pair();
+        let mut queue = OutputQueue::new();
+        let mut buf = Buf::new();
+        queue.push_shared(&mut buf, Arc::new(b"data".to_vec()));
+        queue.write_to(&mut buf, &mut sock, 0);
+    }
+}
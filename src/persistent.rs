@@ -5,8 +5,10 @@ use rotor::{Machine, EventSet, PollOpt, Scope, Response, Time};
 use rotor::void::{unreachable, Void};
 use rotor::{GenericScope};
 
-use {ActiveStream, Protocol, Stream, ProtocolStop, Transport};
+use {ActiveStream, Protocol, Stream, Transport, Stats};
 use extensions::{ResponseExt, ScopeExt};
+use stream::{self, Closed};
+use metrics::report;
 
 
 /// Reconnect timeout in milliseconds.
@@ -24,7 +26,7 @@ pub const CONNECT_TIMEOUT: u64 = 1_000;
 ///
 /// TODO(tailhook) this should include name resolution
 pub struct Persistent<P>(<P::Socket as ActiveStream>::Address,
-                         P::Seed, Fsm<P>)
+                         P::Seed, Fsm<P>, Stats)
     where P: Protocol, P::Socket: ActiveStream;
 
 #[derive(Debug)]
@@ -36,7 +38,7 @@ pub enum Fsm<P: Protocol> {
 }
 
 fn response<P>(addr: <P::Socket as ActiveStream>::Address,
-    seed: P::Seed, fsm: Fsm<P>)
+    seed: P::Seed, fsm: Fsm<P>, stats: Stats)
     -> Response<Persistent<P>, Void>
     where P: Protocol, P::Socket: ActiveStream
 {
@@ -49,7 +51,7 @@ fn response<P>(addr: <P::Socket as ActiveStream>::Address,
         Established(..) => unreachable!(),
         Sleeping(tm) => Some(tm),
     };
-    Response::ok(Persistent(addr, seed, fsm))
+    Response::ok(Persistent(addr, seed, fsm, stats))
         .deadline_opt(timeo)
 }
 
@@ -62,12 +64,20 @@ impl<P> Persistent<P>
             address: <P::Socket as ActiveStream>::Address, seed: P::Seed)
         -> Response<Persistent<P>, Void>
     {
-        Response::ok(Persistent(address, seed, Fsm::Idle))
+        Response::ok(Persistent(address, seed, Fsm::Idle, Stats::empty()))
     }
 
     pub fn connect<S: GenericScope>(scope: &mut S,
             address: <P::Socket as ActiveStream>::Address, seed: P::Seed)
         -> Response<Persistent<P>, Void>
+    {
+        Persistent::reconnect(scope, address, seed, Stats::empty())
+    }
+
+    fn reconnect<S: GenericScope>(scope: &mut S,
+            address: <P::Socket as ActiveStream>::Address, seed: P::Seed,
+            stats: Stats)
+        -> Response<Persistent<P>, Void>
     {
         let fsm = match P::Socket::connect(&address) {
             Ok(sock) => {
@@ -77,10 +87,11 @@ impl<P> Persistent<P>
             }
             Err(e) => {
                 info!("Failed to connect to {:?}: {}", address, e);
+                report(|m| m.connect_failure());
                 Fsm::Sleeping(scope.after(RECONNECT_TIMEOUT))
             }
         };
-        response(address, seed, fsm)
+        response(address, seed, fsm, stats)
     }
 }
 
@@ -111,6 +122,17 @@ impl<P> Persistent<P>
             _ => None,
         }
     }
+    /// Returns I/O statistics summed up for all connections
+    ///
+    /// This includes the current connection (if established) and all the
+    /// connections closed earlier.
+    pub fn stats(&self) -> Stats {
+        let mut stats = self.3.clone();
+        if let Fsm::Established(ref s) = self.2 {
+            stats.add(&s.stats);
+        }
+        stats
+    }
 }
 
 impl<P> Fsm<P>
@@ -119,23 +141,28 @@ impl<P> Fsm<P>
           P::Socket: ActiveStream,
           <P::Socket as ActiveStream>::Address: Debug
 {
-    fn action<S: GenericScope>(resp: Response<Stream<P>, Void>,
+    fn action<S: GenericScope>(res: Result<Stream<P>, Closed>,
         addr: <P::Socket as ActiveStream>::Address,
-        seed: P::Seed, scope: &mut S)
+        seed: P::Seed, mut stats: Stats, scope: &mut S)
         -> Response<Persistent<P>, Void>
     {
-        if resp.is_stopped() {
-            if let Some(err) = resp.cause() {
-                warn!("Connection is failed: {}", err);
-            } else {
-                warn!("Connection is stopped by protocol");
+        match res {
+            Ok(stream) => {
+                let dline = stream::timer(&stream);
+                Response::ok(Persistent(addr, seed,
+                                        Fsm::Established(stream), stats))
+                    .deadline_opt(dline)
+            }
+            Err((err, closed)) => {
+                if let Some(err) = err {
+                    warn!("Connection is failed: {}", err);
+                } else {
+                    warn!("Connection is stopped by protocol");
+                }
+                stats.add(&closed);
+                response(addr, seed,
+                    Fsm::Sleeping(scope.after(RECONNECT_TIMEOUT)), stats)
             }
-            response(addr, seed,
-                Fsm::Sleeping(scope.after(RECONNECT_TIMEOUT)))
-        } else {
-            resp
-                .wrap(Fsm::Established)
-                .wrap(|x| Persistent(addr, seed, x))
         }
     }
 }
@@ -157,32 +184,38 @@ impl<P: Protocol> Machine for Persistent<P>
         -> Response<Self, Self::Seed>
     {
         use self::Fsm::*;
-        let Persistent(addr, seed, state) = self;
+        let Persistent(addr, seed, state, stats) = self;
         let state = match state {
             Idle => Idle,  // spurious event
             Connecting(sock, dline) => {
                 if events.is_writable() {
-                    let resp =  Stream::connected(sock, seed.clone(), scope);
-                    if resp.is_stopped() {
-                        error!("Error creating stream FSM: {}",
-                            resp.cause().unwrap_or(&ProtocolStop));
-                        Fsm::Sleeping(scope.after(RECONNECT_TIMEOUT))
-                    } else {
-                        return Fsm::action(resp, addr, seed, scope);
+                    report(|m| m.connect_success());
+                    match stream::create_connected(sock, seed.clone(), scope)
+                    {
+                        Ok(s) => {
+                            return Fsm::action(Ok(s), addr, seed, stats,
+                                               scope);
+                        }
+                        Err(e) => {
+                            error!("Error creating stream FSM: {}", e);
+                            Fsm::Sleeping(scope.after(RECONNECT_TIMEOUT))
+                        }
                     }
                 } else if events.is_hup() {
                     error!("Connection closed immediately");
+                    report(|m| m.connect_failure());
                     Fsm::Sleeping(scope.after(RECONNECT_TIMEOUT))
                 } else {
                     Connecting(sock, dline) // spurious event
                 }
             }
             Established(x) => {
-                return Fsm::action(x.ready(events, scope), addr, seed, scope);
+                let res = stream::ready(x, events, scope);
+                return Fsm::action(res, addr, seed, stats, scope);
             }
             Sleeping(dline) => Sleeping(dline), // spurious event
         };
-        response(addr, seed, state)
+        response(addr, seed, state, stats)
     }
     fn spawned(self, _scope: &mut Scope<P::Context>)
         -> Response<Self, Self::Seed>
@@ -193,42 +226,45 @@ impl<P: Protocol> Machine for Persistent<P>
         -> Response<Self, Self::Seed>
     {
         use self::Fsm::*;
-        let Persistent(addr, seed, state) = self;
+        let Persistent(addr, seed, state, stats) = self;
         let state = match state {
             Idle => Idle,  // spurious timeout
             Connecting(sock, dline) => {
                 if scope.now() >= dline {
                     warn!("Timeout while establishing connection");
+                    report(|m| m.connect_timeout());
                     Fsm::Sleeping(scope.after(RECONNECT_TIMEOUT))
                 } else {  // spurious timeout
                     Connecting(sock, dline)
                 }
             }
             Established(x) => {
-                return Fsm::action(x.timeout(scope), addr, seed, scope);
+                let res = stream::timer_expired(x, scope);
+                return Fsm::action(res, addr, seed, stats, scope);
             }
             Sleeping(dline) => {
                 if scope.now() >= dline {
-                    return Self::connect(scope, addr, seed);
+                    return Self::reconnect(scope, addr, seed, stats);
                 } else {
                     Sleeping(dline)  // spurious timeout
                 }
             }
         };
-        response(addr, seed, state)
+        response(addr, seed, state, stats)
     }
     fn wakeup(self, scope: &mut Scope<P::Context>)
         -> Response<Self, Self::Seed>
     {
         use self::Fsm::*;
-        let Persistent(addr, seed, state) = self;
+        let Persistent(addr, seed, state, stats) = self;
         let state = match state {
             Established(x) => {
-                return Fsm::action(x.wakeup(scope), addr, seed, scope);
+                let res = stream::wakeup(x, scope);
+                return Fsm::action(res, addr, seed, stats, scope);
             }
             x => x, // spurious wakeup
         };
-        response(addr, seed, state)
+        response(addr, seed, state, stats)
     }
 }
 
@@ -250,7 +286,8 @@ mod replaceable {
     {
         fn empty(&self) -> Self {
             // We assume that cloning is cheap enough. Probably just Copy
-            Persistent(self.0.clone(), self.1.clone(), Fsm::Idle)
+            Persistent(self.0.clone(), self.1.clone(), Fsm::Idle,
+                       self.3.clone())
         }
     }
 }
@@ -26,7 +26,9 @@ quick_error!{
         /// Limit for the number of bytes reached
         ///
         /// This is called when there is alredy maximum bytes in the buffer
-        /// (third argument of `Delimiter`) but no delimiter found.
+        /// (third argument of `Delimiter`) but no delimiter found. Or when
+        /// input buffer reached the limit set by
+        /// `StreamOptions::max_input_buffer`.
         LimitReached {
             description("reached the limit of bytes buffered")
         }
@@ -130,7 +132,8 @@ pub trait Protocol: Sized {
         -> Intent<Self>;
 
     /// Timeout happened, which means either deadline reached in
-    /// Bytes, Delimiter, Flush. Or Sleep has passed.
+    /// Bytes, Delimiter, Flush. Or Sleep has passed. Or no bytes were read
+    /// or written for `StreamOptions::idle_timeout`.
     fn timeout(self, transport: &mut Transport<Self::Socket>,
         scope: &mut Scope<Self::Context>)
         -> Intent<Self>;
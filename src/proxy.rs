@@ -0,0 +1,525 @@
+use std::fmt;
+use std::io;
+use std::cmp::min;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rotor::{Machine, Response, Scope, EventSet, Time};
+use rotor::void::{unreachable, Void};
+
+use stream::{self, Closed};
+use forward::{self, Pump};
+use metrics::report;
+use extensions::{ScopeExt};
+use {Accepted, ActiveStream, StreamSocket, StreamOptions, Stream};
+use {Forward, FORWARD_BUFFER};
+
+
+/// Default time to receive the bytes for the inspection hook of the `Proxy`
+/// in milliseconds
+pub const INSPECT_TIMEOUT: u64 = 10_000;
+
+/// Default time to connect to the upstream of the `Proxy` in milliseconds
+pub const UPSTREAM_CONNECT_TIMEOUT: u64 = 5_000;
+
+
+/// A decision of the inspection hook of the `Proxy`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Inspection<A> {
+    /// More bytes are needed to decide
+    NeedMore,
+    /// Connect to the specified address
+    Connect(A),
+    /// Close the connection
+    Reject,
+}
+
+/// Configuration of the `Proxy`, this is the seed of the state machine
+pub struct ProxyConfig<U: ActiveStream> {
+    address: U::Address,
+    buffer_size: usize,
+    inspect: Option<(usize, Arc<Inspect<U::Address>>)>,
+    inspect_timeout: Duration,
+    connect_timeout: Duration,
+    upstream_options: StreamOptions,
+}
+
+type Inspect<A> = Fn(&[u8]) -> Inspection<A> + Send + Sync;
+
+/// A state machine which forwards the accepted connection to the upstream
+///
+/// For every accepted connection a connection to the upstream is made,
+/// and data is moved in both directions by `Forward`. The `Forward` buffers
+/// limited number of bytes in each direction, so the fast peer is slowed
+/// down to the speed of the slow one. Half-close is propagated, and the
+/// connections are closed when both peers close their sending side or on
+/// the first error.
+///
+/// Both connections are wrapped into a `Stream`: the accepted one gets the
+/// stream options of the `Accept` (see `AcceptOptions::stream_options`),
+/// and the upstream one gets `ProxyConfig::upstream_options`. So the idle
+/// timeout, rate limits, statistics and metrics work as usual.
+///
+/// ```ignore
+/// let config = ProxyConfig::<TcpStream>::new(upstream_address);
+/// Accept::<Proxy<Context, TcpStream, TcpStream>, _>::new(
+///     listener, config, scope)
+/// ```
+///
+/// Optionally the first bytes of the connection may be inspected before
+/// connecting to the upstream (see `ProxyConfig::inspect`).
+pub struct Proxy<C, S: StreamSocket, U: ActiveStream>(State<C, S, U>);
+
+enum State<C, S: StreamSocket, U: ActiveStream> {
+    // Connection to the upstream is started on the next iteration of the
+    // loop because `Machine::create` can't fail
+    Start(Stream<Pump<C, S>>, ProxyConfig<U>),
+    // The client stream, the upstream stream, connect deadline
+    Connect(Stream<Pump<C, S>>, Stream<Pump<C, U>>, Time, ProxyConfig<U>),
+    // The client stream, number of bytes inspected, inspection deadline
+    Inspect(Stream<Pump<C, S>>, usize, Time, ProxyConfig<U>),
+    Forward(Forward<C, S, U>),
+}
+
+impl<U: ActiveStream> ProxyConfig<U> {
+    /// Proxy connections to the `address`
+    pub fn new(address: U::Address) -> ProxyConfig<U> {
+        ProxyConfig {
+            address: address,
+            buffer_size: FORWARD_BUFFER,
+            inspect: None,
+            inspect_timeout: Duration::from_millis(INSPECT_TIMEOUT),
+            connect_timeout: Duration::from_millis(UPSTREAM_CONNECT_TIMEOUT),
+            upstream_options: StreamOptions::new(),
+        }
+    }
+    /// Number of bytes buffered in each direction
+    pub fn buffer_size(mut self, bytes: usize) -> ProxyConfig<U> {
+        self.buffer_size = bytes;
+        self
+    }
+    /// Inspect the first bytes received from the client before connecting
+    ///
+    /// The hook is called every time new bytes are received (the bytes
+    /// received so far, up to `max_bytes`, are passed) until it returns
+    /// something other than `Inspection::NeedMore`. It may choose the
+    /// address to connect to, instead of the one passed to
+    /// `ProxyConfig::new`, or reject the connection. The inspected bytes are
+    /// sent to the upstream as usual.
+    ///
+    /// The connection is closed if decision is not made after receiving
+    /// `max_bytes` or when the inspection timeout expires.
+    pub fn inspect<F>(mut self, max_bytes: usize, hook: F) -> ProxyConfig<U>
+        where F: Fn(&[u8]) -> Inspection<U::Address> + Send + Sync + 'static
+    {
+        assert!(max_bytes > 0);
+        self.inspect = Some((max_bytes, Arc::new(hook)));
+        self
+    }
+    /// Time to receive the bytes for the inspection hook
+    pub fn inspect_timeout(mut self, timeout: Duration) -> ProxyConfig<U> {
+        self.inspect_timeout = timeout;
+        self
+    }
+    /// Time to establish the connection to the upstream
+    ///
+    /// Both connections are closed if the upstream isn't connected in
+    /// time. Default is `UPSTREAM_CONNECT_TIMEOUT`.
+    pub fn connect_timeout(mut self, timeout: Duration) -> ProxyConfig<U> {
+        self.connect_timeout = timeout;
+        self
+    }
+    /// Options of the stream of the upstream connection
+    ///
+    /// Options of the accepted connection are set by
+    /// `AcceptOptions::stream_options`.
+    pub fn upstream_options(mut self, options: StreamOptions)
+        -> ProxyConfig<U>
+    {
+        self.upstream_options = options;
+        self
+    }
+}
+
+impl<U: ActiveStream> Clone for ProxyConfig<U>
+    where U::Address: Clone
+{
+    fn clone(&self) -> ProxyConfig<U> {
+        ProxyConfig {
+            address: self.address.clone(),
+            buffer_size: self.buffer_size,
+            inspect: self.inspect.clone(),
+            inspect_timeout: self.inspect_timeout,
+            connect_timeout: self.connect_timeout,
+            upstream_options: self.upstream_options.clone(),
+        }
+    }
+}
+
+impl<C, S: StreamSocket, U: ActiveStream> Proxy<C, S, U> {
+    fn connect(client: Stream<Pump<C, S>>, upstream: io::Result<U>,
+        config: ProxyConfig<U>, scope: &mut Scope<C>)
+        -> Response<Self, Void>
+    {
+        let upstream = match upstream {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                stream::abort(client);
+                return Response::error(Box::new(e));
+            }
+        };
+        let upstream = match forward::open(upstream,
+            config.upstream_options.clone(), scope)
+        {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                stream::abort(client);
+                return Response::error(e);
+            }
+        };
+        let dline = scope.now() + config.connect_timeout;
+        Proxy::connecting(client, upstream, dline, config, scope)
+    }
+    // Both sockets share the token, so instead of looking at the events,
+    // the state of the upstream socket is checked on every event
+    fn connecting(client: Stream<Pump<C, S>>, upstream: Stream<Pump<C, U>>,
+        dline: Time, config: ProxyConfig<U>, scope: &mut Scope<C>)
+        -> Response<Self, Void>
+    {
+        if let Err(e) = upstream.socket.take_socket_error() {
+            debug!("Error connecting to the upstream: {}", e);
+            report(|m| m.connect_failure());
+            stream::abort(client);
+            stream::abort(upstream);
+            return Response::done();
+        }
+        if upstream.socket.is_connected() {
+            report(|m| m.connect_success());
+            // The inspected bytes are in the input buffer of the client
+            // stream, so they are sent to the upstream first
+            return forward::join(client, upstream, config.buffer_size, scope)
+                .wrap(forwarding);
+        }
+        if scope.reached(Some(dline)) {
+            debug!("Timeout while connecting to the upstream");
+            report(|m| m.connect_timeout());
+            stream::abort(client);
+            stream::abort(upstream);
+            return Response::done();
+        }
+        let me = State::Connect(client, upstream, dline, config);
+        Response::ok(Proxy(me)).deadline(dline)
+    }
+    fn inspect(mut client: Stream<Pump<C, S>>, mut inspected: usize,
+        dline: Time, config: ProxyConfig<U>, scope: &mut Scope<C>)
+        -> Response<Self, Void>
+    {
+        let (max, hook) = match config.inspect {
+            Some((max, ref hook)) => (max, hook.clone()),
+            None => unreachable!(),
+        };
+        loop {
+            let len = min(client.inbuf.len(), max);
+            if len > inspected {
+                inspected = len;
+                match hook(&client.inbuf[..len]) {
+                    Inspection::NeedMore if len < max => {}
+                    Inspection::NeedMore | Inspection::Reject => {
+                        stream::abort(client);
+                        return Response::done();
+                    }
+                    Inspection::Connect(address) => {
+                        let upstream = U::connect(&address);
+                        return Proxy::connect(client, upstream,
+                            config, scope);
+                    }
+                }
+            }
+            if forward::is_eof(&client) {
+                debug!("Connection closed before the inspection is done");
+                stream::abort(client);
+                return Response::done();
+            }
+            let read = client.inbuf.len();
+            client = match forward::read_more(client, scope) {
+                Ok(client) => client,
+                Err(closed) => return from_closed(closed),
+            };
+            if client.inbuf.len() == read && !forward::is_eof(&client) {
+                let timer = stream::timer(&client)
+                    .map(|x| min(x, dline)).unwrap_or(dline);
+                let me = State::Inspect(client, inspected, dline, config);
+                return Response::ok(Proxy(me)).deadline(timer);
+            }
+        }
+    }
+    fn resume(res: Result<Stream<Pump<C, S>>, Closed>, inspected: usize,
+        dline: Time, config: ProxyConfig<U>, scope: &mut Scope<C>)
+        -> Response<Self, Void>
+    {
+        match res {
+            Ok(client) => Proxy::inspect(client, inspected, dline, config,
+                                         scope),
+            Err(closed) => from_closed(closed),
+        }
+    }
+}
+
+fn forwarding<C, S, U>(fwd: Forward<C, S, U>) -> Proxy<C, S, U>
+    where S: StreamSocket, U: ActiveStream
+{
+    Proxy(State::Forward(fwd))
+}
+
+fn from_closed<C, S, U>(closed: Closed) -> Response<Proxy<C, S, U>, Void>
+    where S: StreamSocket, U: ActiveStream
+{
+    match closed {
+        (Some(e), _) => Response::error(e),
+        (None, _) => Response::done(),
+    }
+}
+
+impl<C, S: StreamSocket, U: ActiveStream> Accepted for Proxy<C, S, U>
+    where U::Address: Clone
+{
+    type Seed = ProxyConfig<U>;
+    type Socket = S;
+    fn accepted(sock: S, config: ProxyConfig<U>, scope: &mut Scope<C>)
+        -> Response<Self, Void>
+    {
+        Proxy::accepted_with_options(sock, config, &StreamOptions::new(),
+                                     scope)
+    }
+    fn accepted_with_options(sock: S, config: ProxyConfig<U>,
+        options: &StreamOptions, scope: &mut Scope<C>)
+        -> Response<Self, Void>
+    {
+        let client = match forward::open(sock, options.clone(), scope) {
+            Ok(client) => client,
+            Err(e) => return Response::error(e),
+        };
+        if config.inspect.is_some() {
+            // The first readable event starts the inspection
+            let dline = scope.now() + config.inspect_timeout;
+            let me = State::Inspect(client, 0, dline, config);
+            Response::ok(Proxy(me)).deadline(dline)
+        } else {
+            let now = scope.now();
+            Response::ok(Proxy(State::Start(client, config))).deadline(now)
+        }
+    }
+}
+
+impl<C, S: StreamSocket, U: ActiveStream> Machine for Proxy<C, S, U> {
+    type Context = C;
+    type Seed = Void;
+    fn create(void: Void, _scope: &mut Scope<C>) -> Response<Self, Void> {
+        unreachable(void);
+    }
+    fn ready(self, events: EventSet, scope: &mut Scope<C>)
+        -> Response<Self, Void>
+    {
+        match self.0 {
+            State::Start(client, config) => {
+                let upstream = U::connect(&config.address);
+                Proxy::connect(client, upstream, config, scope)
+            }
+            State::Connect(client, upstream, dline, config) => {
+                Proxy::connecting(client, upstream, dline, config, scope)
+            }
+            State::Inspect(client, inspected, dline, config) => {
+                // Like in `Forward`, the hang up is reported by the read,
+                // so the data received before it is inspected
+                let events = EventSet::readable() | EventSet::writable();
+                let res = stream::ready(client, events, scope);
+                Proxy::resume(res, inspected, dline, config, scope)
+            }
+            State::Forward(fwd) => fwd.ready(events, scope).wrap(forwarding),
+        }
+    }
+    fn spawned(self, _scope: &mut Scope<C>) -> Response<Self, Void> {
+        unreachable!();
+    }
+    fn timeout(self, scope: &mut Scope<C>) -> Response<Self, Void> {
+        match self.0 {
+            State::Start(client, config) => {
+                let upstream = U::connect(&config.address);
+                Proxy::connect(client, upstream, config, scope)
+            }
+            State::Connect(client, upstream, dline, config) => {
+                Proxy::connecting(client, upstream, dline, config, scope)
+            }
+            State::Inspect(client, inspected, dline, config) => {
+                if scope.reached(Some(dline)) {
+                    debug!("Timeout while inspecting the connection");
+                    stream::abort(client);
+                    return Response::done();
+                }
+                let res = if scope.reached(stream::timer(&client)) {
+                    stream::timer_expired(client, scope)
+                } else {
+                    Ok(client)
+                };
+                Proxy::resume(res, inspected, dline, config, scope)
+            }
+            State::Forward(fwd) => fwd.timeout(scope).wrap(forwarding),
+        }
+    }
+    fn wakeup(self, scope: &mut Scope<C>) -> Response<Self, Void> {
+        match self.0 {
+            State::Start(client, config) => {
+                let upstream = U::connect(&config.address);
+                Proxy::connect(client, upstream, config, scope)
+            }
+            State::Connect(client, upstream, dline, config) => {
+                Proxy::connecting(client, upstream, dline, config, scope)
+            }
+            State::Inspect(client, inspected, dline, config) => {
+                let res = stream::wakeup(client, scope);
+                Proxy::resume(res, inspected, dline, config, scope)
+            }
+            State::Forward(fwd) => fwd.wakeup(scope).wrap(forwarding),
+        }
+    }
+}
+
+impl<C, S: StreamSocket, U: ActiveStream> fmt::Debug for Proxy<C, S, U> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            State::Start(..) => write!(f, "Proxy::Start"),
+            State::Connect(..) => write!(f, "Proxy::Connect"),
+            State::Inspect(..) => write!(f, "Proxy::Inspect"),
+            State::Forward(ref fwd) => write!(f, "Proxy::{:?}", fwd),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener as StdListener, TcpStream as StdStream};
+    use std::net::Shutdown;
+    use std::time::Duration;
+
+    use rotor::{Loop, Config, Machine, EventSet, Time};
+    use rotor::mio::tcp::{TcpListener, TcpStream};
+    use testing::{with_scope, pair, MockSocket};
+    use super::{Proxy, ProxyConfig, Inspection};
+    use {Accept, Accepted, Drain, StreamOptions};
+
+    #[test]
+    fn proxy() {
+        let upstream = StdListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let upstream = thread::spawn(move || {
+            // Echoes everything back after the client closes sending side
+            let (mut sock, _) = upstream.accept().unwrap();
+            let mut data = Vec::new();
+            sock.read_to_end(&mut data).unwrap();
+            sock.write_all(&data).unwrap();
+        });
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap())
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ProxyConfig::<TcpStream>::new(upstream_addr)
+            .inspect(4, move |data| match data {
+                x if x.len() < 4 => Inspection::NeedMore,
+                b"PING" => Inspection::Connect(upstream_addr),
+                _ => Inspection::Reject,
+            });
+        let drain = Drain::new();
+        let handle = drain.clone();
+        let server = thread::spawn(move || {
+            let mut lp = Loop::new(&Config::new()).unwrap();
+            lp.add_machine_with(|scope| {
+                Accept::<Proxy<(), _, TcpStream>, _>::with_drain(
+                    listener, config, &handle, scope)
+            }).unwrap();
+            lp.run(()).unwrap();
+        });
+
+        let mut client = StdStream::connect(addr).unwrap();
+        client.write_all(b"PING hello").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut data = Vec::new();
+        client.read_to_end(&mut data).unwrap();
+        assert_eq!(&data[..], b"PING hello");
+
+        let mut client = StdStream::connect(addr).unwrap();
+        client.write_all(b"QUIT").unwrap();
+        let mut data = Vec::new();
+        client.read_to_end(&mut data).unwrap();
+        assert_eq!(&data[..], b"");
+
+        upstream.join().unwrap();
+        // The loop exits when all the connections are closed
+        drain.start();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let upstream = StdListener::bind("127.0.0.1:0").unwrap();
+        let config = ProxyConfig::<TcpStream>::new(
+            upstream.local_addr().unwrap());
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap())
+            .unwrap();
+        let _client = StdStream::connect(listener.local_addr().unwrap())
+            .unwrap();
+        let (sock, _) = listener.accept().unwrap().unwrap();
+        let options = StreamOptions::new()
+            .idle_timeout(Duration::from_millis(100));
+        let proxy = with_scope(&mut (), Time::zero(), |scope| {
+            let proxy = Proxy::<(), _, TcpStream>::accepted_with_options(
+                sock, config, &options, scope).expect_machine();
+            // Connects to the upstream on the next iteration of the loop
+            proxy.timeout(scope).expect_machine()
+        });
+        let later = Time::zero() + Duration::from_millis(200);
+        with_scope(&mut (), later, |scope| {
+            proxy.timeout(scope).expect_done();
+        });
+    }
+
+    #[test]
+    fn connect_timeout() {
+        let (client, _client_peer) = pair();
+        let (_upstream, upstream_peer) = pair();
+        upstream_peer.set_connected(false);
+        let config = ProxyConfig::<MockSocket>::new(upstream_peer.clone())
+            .connect_timeout(Duration::from_millis(100));
+        let proxy = with_scope(&mut (), Time::zero(), |scope| {
+            let proxy = Proxy::<(), _, MockSocket>::accepted(
+                client, config, scope).expect_machine();
+            let proxy = proxy.timeout(scope).expect_machine();
+            let proxy = proxy.ready(EventSet::writable(), scope)
+                .expect_machine();
+            assert_eq!(format!("{:?}", proxy), "Proxy::Connect");
+            proxy
+        });
+        let later = Time::zero() + Duration::from_millis(200);
+        with_scope(&mut (), later, |scope| {
+            proxy.timeout(scope).expect_done();
+        });
+    }
+
+    #[test]
+    fn connected() {
+        let (client, _client_peer) = pair();
+        let (_upstream, upstream_peer) = pair();
+        upstream_peer.set_connected(false);
+        let config = ProxyConfig::<MockSocket>::new(upstream_peer.clone())
+            .connect_timeout(Duration::from_millis(100));
+        with_scope(&mut (), Time::zero(), |scope| {
+            let proxy = Proxy::<(), _, MockSocket>::accepted(
+                client, config, scope).expect_machine();
+            let proxy = proxy.timeout(scope).expect_machine();
+            upstream_peer.set_connected(true);
+            let proxy = proxy.ready(EventSet::writable(), scope)
+                .expect_machine();
+            assert!(format!("{:?}", proxy).starts_with("Proxy::Forward"));
+        });
+    }
+}
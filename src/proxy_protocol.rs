@@ -0,0 +1,496 @@
+use std::fmt;
+use std::cmp::max;
+use std::error::Error;
+use std::str::from_utf8;
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr, Ipv6Addr};
+use std::io::ErrorKind::{WouldBlock, BrokenPipe, ConnectionReset};
+
+use rotor::{Machine, Response, Scope, EventSet, PollOpt, Time};
+use rotor::void::{unreachable, Void};
+
+use stream;
+use metrics::report;
+use {Accepted, Protocol, Stream, Stats, Buf};
+use {InvalidProxyHeader, StreamOptions};
+
+
+/// Default time to receive the PROXY protocol header in milliseconds
+///
+/// Use `StreamOptions::proxy_header_timeout` to override.
+pub const PROXY_HEADER_TIMEOUT: u64 = 10_000;
+
+const V1_PREFIX: &'static [u8] = b"PROXY ";
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: &'static [u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+
+/// Addresses received in the PROXY protocol header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxyHeader {
+    /// Address of the client that has connected to the proxy
+    pub source: SocketAddr,
+    /// Address the client has connected to (i.e. address of the proxy)
+    pub destination: SocketAddr,
+}
+
+/// A stream which starts with a PROXY protocol header
+///
+/// Use it instead of `Stream` to accept connections from HAProxy, AWS
+/// Network Load Balancer and other proxies supporting the protocol:
+///
+/// ```ignore
+/// Accept::<ProxyProtocolStream<Http>, _>::new(listener, seed, scope)
+/// ```
+///
+/// Both the text (v1) and the binary (v2) versions of the header are
+/// supported. The header is read and parsed before `Protocol::create` is
+/// called, and addresses are available using `Transport::proxy_header()`.
+/// Connections without a valid header are closed. The bytes of the header
+/// are counted in the `Stats` of the stream.
+pub enum ProxyProtocolStream<P: Protocol> {
+    Header(P::Socket, P::Seed, Buf, Stats, Time, StreamOptions),
+    Stream(Stream<P>),
+}
+
+/// Parses the PROXY protocol header
+///
+/// Returns `Ok(None)` if more bytes are needed. Otherwise returns number of
+/// bytes the header occupies and the addresses. The addresses are `None`
+/// if proxy doesn't pass them (i.e. for `UNKNOWN` protocol, `LOCAL`
+/// command or unix sockets).
+pub fn parse_header(buf: &[u8])
+    -> Result<Option<(usize, Option<ProxyHeader>)>, InvalidProxyHeader>
+{
+    if buf.len() == 0 {
+        Ok(None)
+    } else if buf[0] == V1_PREFIX[0] {
+        parse_v1(buf)
+    } else if buf[0] == V2_SIGNATURE[0] {
+        parse_v2(buf)
+    } else {
+        Err(InvalidProxyHeader("no PROXY protocol signature"))
+    }
+}
+
+fn parse_v1(buf: &[u8])
+    -> Result<Option<(usize, Option<ProxyHeader>)>, InvalidProxyHeader>
+{
+    let prefix = ::std::cmp::min(buf.len(), V1_PREFIX.len());
+    if &buf[..prefix] != &V1_PREFIX[..prefix] {
+        return Err(InvalidProxyHeader("no PROXY protocol signature"));
+    }
+    let end = match buf.iter().take(V1_MAX_LENGTH).position(|&x| x == b'\n')
+    {
+        Some(end) if end > 0 && buf[end-1] == b'\r' => end - 1,
+        Some(_) => return Err(InvalidProxyHeader("bad line ending")),
+        None if buf.len() >= V1_MAX_LENGTH => {
+            return Err(InvalidProxyHeader("header is too long"));
+        }
+        None => return Ok(None),
+    };
+    let line = try!(from_utf8(&buf[V1_PREFIX.len()..end])
+        .map_err(|_| InvalidProxyHeader("header is not ascii")));
+    let mut words = line.split(' ');
+    let proto = words.next();
+    let header = match proto {
+        Some("TCP4") | Some("TCP6") => {
+            let mut fields = [""; 4];
+            for f in fields.iter_mut() {
+                *f = try!(words.next()
+                    .ok_or(InvalidProxyHeader("too few fields")));
+            }
+            if words.next().is_some() {
+                return Err(InvalidProxyHeader("too many fields"));
+            }
+            let v4 = proto == Some("TCP4");
+            let parse = |ip: &str, port: &str| {
+                let port = try!(port.parse::<u16>()
+                    .map_err(|_| InvalidProxyHeader("invalid port")));
+                if v4 {
+                    ip.parse::<Ipv4Addr>()
+                    .map(|ip| SocketAddr::V4(SocketAddrV4::new(ip, port)))
+                } else {
+                    ip.parse::<Ipv6Addr>()
+                    .map(|ip| SocketAddr::V6(SocketAddrV6::new(ip, port,
+                                                               0, 0)))
+                }.map_err(|_| InvalidProxyHeader("invalid address"))
+            };
+            Some(ProxyHeader {
+                source: try!(parse(fields[0], fields[2])),
+                destination: try!(parse(fields[1], fields[3])),
+            })
+        }
+        Some("UNKNOWN") => None,
+        _ => return Err(InvalidProxyHeader("unsupported protocol")),
+    };
+    Ok(Some((end + 2, header)))
+}
+
+fn parse_v2(buf: &[u8])
+    -> Result<Option<(usize, Option<ProxyHeader>)>, InvalidProxyHeader>
+{
+    let prefix = ::std::cmp::min(buf.len(), V2_SIGNATURE.len());
+    if &buf[..prefix] != &V2_SIGNATURE[..prefix] {
+        return Err(InvalidProxyHeader("no PROXY protocol signature"));
+    }
+    if buf.len() < 16 {
+        return Ok(None);
+    }
+    if buf[12] >> 4 != 2 {
+        return Err(InvalidProxyHeader("unsupported version"));
+    }
+    let len = ((buf[14] as usize) << 8) | buf[15] as usize;
+    if buf.len() < 16 + len {
+        return Ok(None);
+    }
+    let data = &buf[16..16+len];
+    let header = match (buf[12] & 0xF, buf[13] >> 4, buf[13] & 0xF) {
+        // LOCAL command, addresses should be ignored
+        (0, _, _) => None,
+        // Unspecified protocol
+        (1, 0, 0) => None,
+        // Only STREAM transport is valid for a stream socket
+        (1, _, t) if t != 1 => {
+            return Err(InvalidProxyHeader("unsupported transport"));
+        }
+        (1, 1, _) if len >= 12 => {
+            let ip = |x: &[u8]| Ipv4Addr::new(x[0], x[1], x[2], x[3]);
+            Some(ProxyHeader {
+                source: SocketAddr::V4(SocketAddrV4::new(
+                    ip(&data[0..4]), port(&data[8..10]))),
+                destination: SocketAddr::V4(SocketAddrV4::new(
+                    ip(&data[4..8]), port(&data[10..12]))),
+            })
+        }
+        (1, 2, _) if len >= 36 => {
+            let ip = |x: &[u8]| Ipv6Addr::new(
+                port(&x[0..2]), port(&x[2..4]),
+                port(&x[4..6]), port(&x[6..8]),
+                port(&x[8..10]), port(&x[10..12]),
+                port(&x[12..14]), port(&x[14..16]));
+            Some(ProxyHeader {
+                source: SocketAddr::V6(SocketAddrV6::new(
+                    ip(&data[0..16]), port(&data[32..34]), 0, 0)),
+                destination: SocketAddr::V6(SocketAddrV6::new(
+                    ip(&data[16..32]), port(&data[34..36]), 0, 0)),
+            })
+        }
+        (1, 1, _) | (1, 2, _) => {
+            return Err(InvalidProxyHeader("address block is too short"));
+        }
+        // Unix sockets
+        (1, _, _) => None,
+        _ => return Err(InvalidProxyHeader("unsupported command")),
+    };
+    Ok(Some((16 + len, header)))
+}
+
+fn port(x: &[u8]) -> u16 {
+    ((x[0] as u16) << 8) | x[1] as u16
+}
+
+impl<P: Protocol> ProxyProtocolStream<P> {
+    fn start(sock: P::Socket, seed: P::Seed, inbuf: Buf, stats: Stats,
+        header: Option<ProxyHeader>, options: StreamOptions,
+        scope: &mut Scope<P::Context>)
+        -> Response<Self, Void>
+    {
+        match stream::create_with_stats(sock, seed, true, options, stats,
+                                        scope)
+        {
+            Ok(mut stream) => {
+                stream.inbuf = inbuf;
+                stream.proxy = header.map(Box::new);
+                // The bytes after the header might already be in the
+                // buffer, and we will not receive another edge-triggered
+                // event for them, so process them right now
+                stream.ready(EventSet::readable(), scope)
+                    .wrap(ProxyProtocolStream::Stream)
+            }
+            Err(e) => Response::error(e),
+        }
+    }
+}
+
+// The connection is closed before the header is received
+fn close<M>(err: Option<Box<Error>>, stats: Stats) -> Response<M, Void> {
+    report(|m| m.connection_closed(&stats));
+    match err {
+        Some(e) => Response::error(e),
+        None => Response::done(),
+    }
+}
+
+impl<P: Protocol> fmt::Debug for ProxyProtocolStream<P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProxyProtocolStream::Header(..) => {
+                write!(f, "ProxyProtocolStream::Header")
+            }
+            ProxyProtocolStream::Stream(..) => {
+                write!(f, "ProxyProtocolStream::Stream")
+            }
+        }
+    }
+}
+
+impl<P: Protocol> Accepted for ProxyProtocolStream<P>
+    where P::Seed: Clone
+{
+    type Seed = P::Seed;
+    type Socket = P::Socket;
+    fn accepted(sock: P::Socket, seed: P::Seed,
+        scope: &mut Scope<Self::Context>)
+        -> Response<Self, Void>
+    {
+        Self::accepted_with_options(sock, seed, &StreamOptions::new(), scope)
+    }
+    fn accepted_with_options(sock: P::Socket, seed: P::Seed,
+        options: &StreamOptions, scope: &mut Scope<Self::Context>)
+        -> Response<Self, Void>
+    {
+        // Register the same way as `Stream` does, so we don't need to
+        // reregister when header is received
+        if let Err(e) = scope.register(&sock,
+            EventSet::all(), PollOpt::edge())
+        {
+            return close(Some(Box::new(e)), Stats::new());
+        }
+        let dline = scope.now() + options.proxy_header_timeout;
+        let buf = Buf::new();
+        Response::ok(ProxyProtocolStream::Header(sock, seed, buf,
+            Stats::new(), dline, options.clone()))
+            .deadline(dline)
+    }
+}
+
+impl<P: Protocol> Machine for ProxyProtocolStream<P> {
+    type Context = P::Context;
+    type Seed = Void;
+    fn create(void: Void, _scope: &mut Scope<Self::Context>)
+        -> Response<Self, Void>
+    {
+        unreachable(void);
+    }
+    fn ready(self, events: EventSet, scope: &mut Scope<Self::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        use self::ProxyProtocolStream::*;
+        match self {
+            Header(mut sock, seed, mut buf, mut stats, dline, opt) => {
+                // Hup and error events are not checked, the header may
+                // be received together with them, and otherwise the read
+                // returns either an end of stream or the error
+                loop {
+                    stats.read_calls += 1;
+                    match buf.read_from(&mut sock) {
+                        Ok(0) => {
+                            debug!("Connection closed before \
+                                    PROXY header received");
+                            return close(None, stats);
+                        }
+                        Ok(bytes) => {
+                            report(|m| m.bytes_read(bytes));
+                            stats.bytes_read += bytes as u64;
+                            stats.max_inbuf = max(stats.max_inbuf,
+                                                  buf.len());
+                        }
+                        Err(ref e) if e.kind() == BrokenPipe
+                                   || e.kind() == ConnectionReset
+                        => return close(None, stats),
+                        Err(ref e) if e.kind() == WouldBlock => {
+                            stats.read_would_block += 1;
+                            let me = Header(sock, seed, buf, stats,
+                                            dline, opt);
+                            return Response::ok(me).deadline(dline);
+                        }
+                        Err(e) => return close(Some(Box::new(e)), stats),
+                    }
+                    match parse_header(&buf[..]) {
+                        Ok(Some((bytes, header))) => {
+                            buf.consume(bytes);
+                            return ProxyProtocolStream::start(
+                                sock, seed, buf, stats, header, opt, scope);
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            info!("Bad PROXY protocol header: {}", e);
+                            return close(Some(Box::new(e)), stats);
+                        }
+                    }
+                }
+            }
+            Stream(s) => s.ready(events, scope).wrap(Stream),
+        }
+    }
+    fn spawned(self, _scope: &mut Scope<Self::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        unreachable!();
+    }
+    fn timeout(self, scope: &mut Scope<Self::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        use self::ProxyProtocolStream::*;
+        match self {
+            Header(sock, seed, buf, stats, dline, opt) => {
+                if scope.now() >= dline {
+                    info!("Timeout while waiting for PROXY header");
+                    close(None, stats)
+                } else {
+                    Response::ok(Header(sock, seed, buf, stats, dline, opt))
+                        .deadline(dline)
+                }
+            }
+            Stream(s) => s.timeout(scope).wrap(Stream),
+        }
+    }
+    fn wakeup(self, scope: &mut Scope<Self::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        use self::ProxyProtocolStream::*;
+        match self {
+            Header(sock, seed, buf, stats, dline, opt) => {
+                Response::ok(Header(sock, seed, buf, stats, dline, opt))
+                    .deadline(dline)
+            }
+            Stream(s) => s.wakeup(scope).wrap(Stream),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use rotor::{Machine, EventSet, Time};
+    use testing::{pair, with_scope, MockSocket};
+    use testing::echo::Echo;
+    use {Accepted, StreamOptions};
+    use super::{parse_header, ProxyHeader, ProxyProtocolStream};
+
+    type Proxied = ProxyProtocolStream<Echo<MockSocket>>;
+
+    fn header(src: &str, dst: &str) -> Option<ProxyHeader> {
+        Some(ProxyHeader {
+            source: src.parse().unwrap(),
+            destination: dst.parse().unwrap(),
+        })
+    }
+
+    #[test]
+    fn v1_tcp4() {
+        assert_eq!(parse_header(
+            b"PROXY TCP4 1.2.3.4 5.6.7.8 1111 80\r\nGET /").unwrap(),
+            Some((36, header("1.2.3.4:1111", "5.6.7.8:80"))));
+    }
+
+    #[test]
+    fn v1_tcp6() {
+        assert_eq!(parse_header(
+            b"PROXY TCP6 ::1 2001:db8::2 1111 80\r\n").unwrap(),
+            Some((36, header("[::1]:1111", "[2001:db8::2]:80"))));
+    }
+
+    #[test]
+    fn v1_unknown() {
+        assert_eq!(parse_header(b"PROXY UNKNOWN\r\n").unwrap(),
+            Some((15, None)));
+    }
+
+    #[test]
+    fn v1_partial() {
+        assert_eq!(parse_header(b"PRO").unwrap(), None);
+        assert_eq!(parse_header(b"PROXY TCP4 1.2.3.4").unwrap(), None);
+    }
+
+    #[test]
+    fn v1_invalid() {
+        assert!(parse_header(b"GET / HTTP/1.0\r\n").is_err());
+        assert!(parse_header(b"PROXY TCP4 1.2.3.4 x 1 2\r\n").is_err());
+        assert!(parse_header(&[b'P'; 200]).is_err());
+    }
+
+    #[test]
+    fn v1_wrong_family() {
+        assert!(parse_header(b"PROXY TCP4 ::1 ::2 1111 80\r\n").is_err());
+        assert!(parse_header(b"PROXY TCP6 1.2.3.4 5.6.7.8 1111 80\r\n")
+            .is_err());
+        assert!(parse_header(b"PROXY TCP4 1.2.3.4 5.6.7.8 1111 70000\r\n")
+            .is_err());
+    }
+
+    #[test]
+    fn v2_tcp4() {
+        let mut buf = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c".to_vec();
+        buf.extend(&[1, 2, 3, 4, 5, 6, 7, 8, 0x04, 0x57, 0, 80]);
+        buf.extend(b"GET /");
+        assert_eq!(parse_header(&buf[..20]).unwrap(), None);
+        assert_eq!(parse_header(&buf).unwrap(),
+            Some((28, header("1.2.3.4:1111", "5.6.7.8:80"))));
+    }
+
+    #[test]
+    fn v2_local() {
+        let buf = b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00";
+        assert_eq!(parse_header(buf).unwrap(), Some((16, None)));
+    }
+
+    #[test]
+    fn v2_datagram() {
+        let mut buf = b"\r\n\r\n\0\r\nQUIT\n\x21\x12\x00\x0c".to_vec();
+        buf.extend(&[1, 2, 3, 4, 5, 6, 7, 8, 0x04, 0x57, 0, 80]);
+        assert!(parse_header(&buf).is_err());
+    }
+
+    #[test]
+    fn header_with_hup() {
+        let (sock, peer) = pair();
+        peer.push(b"PROXY TCP4 1.2.3.4 5.6.7.8 1111 80\r\nhello\n");
+        peer.push_eof();
+        with_scope(&mut (), Time::zero(), |scope| {
+            let m = Proxied::accepted(sock, (), scope).expect_machine();
+            // Header and data received with the hangup are processed
+            // before the connection is closed
+            m.ready(EventSet::readable() | EventSet::hup(), scope)
+                .expect_done();
+        });
+        assert_eq!(peer.output(), b"hello\n");
+    }
+
+    #[test]
+    fn header_stats() {
+        let (sock, peer) = pair();
+        peer.push(b"PROXY TCP4 1.2.3.4 5.6.7.8 1111 80\r\nhello\n");
+        with_scope(&mut (), Time::zero(), |scope| {
+            let m = Proxied::accepted(sock, (), scope).expect_machine();
+            match m.ready(EventSet::readable(), scope).expect_machine() {
+                ProxyProtocolStream::Stream(s) => {
+                    // The header is counted too
+                    assert_eq!(s.stats.bytes_read, 42);
+                }
+                _ => unreachable!(),
+            }
+        });
+        assert_eq!(peer.output(), b"hello\n");
+    }
+
+    #[test]
+    fn header_timeout() {
+        let (sock, _peer) = pair();
+        let options = StreamOptions::new()
+            .proxy_header_timeout(Duration::new(1, 0));
+        let m = with_scope(&mut (), Time::zero(), |scope| {
+            Proxied::accepted_with_options(sock, (), &options, scope)
+                .expect_machine()
+        });
+        let half = Time::zero() + Duration::from_millis(500);
+        let m = with_scope(&mut (), half, |scope| {
+            m.timeout(scope).expect_machine()
+        });
+        let later = Time::zero() + Duration::new(1, 0);
+        with_scope(&mut (), later, |scope| {
+            m.timeout(scope).expect_done();
+        });
+    }
+}
@@ -0,0 +1,131 @@
+use std::cmp::max;
+use std::time::Duration;
+
+use rotor::Time;
+
+
+/// Rate limiter for a single direction of the stream
+///
+/// This is a token bucket implemented as a virtual scheduling algorithm
+/// (GCRA), because `rotor::Time` can only be compared and advanced by
+/// a duration, but elapsed time can't be computed.
+///
+/// The `next` is the time when the transfer is allowed again. Each transfer
+/// moves it forward by the time the transfer takes at the specified rate.
+/// While it's not reached, nothing is transferred, otherwise up to `burst`
+/// bytes may be transferred at once.
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    rate: u64,
+    burst: usize,
+    next: Option<Time>,
+    // Nanoseconds not accounted in `next`, as it has millisecond precision
+    carry: u64,
+}
+
+impl RateLimit {
+    pub fn new(bytes_per_second: u64) -> RateLimit {
+        assert!(bytes_per_second > 0);
+        RateLimit {
+            rate: bytes_per_second,
+            // bytes transferred in 100 ms
+            burst: max(bytes_per_second / 10, 1) as usize,
+            next: None,
+            carry: 0,
+        }
+    }
+    /// Returns number of bytes that may be transferred now, or the time
+    /// when transfer is allowed again
+    pub fn allowed(&self, now: Time) -> Result<usize, Time> {
+        match self.next {
+            Some(next) if next > now => Err(next),
+            _ => Ok(self.burst),
+        }
+    }
+    /// Accounts the bytes transferred
+    pub fn transferred(&mut self, now: Time, bytes: usize) {
+        let start = match self.next {
+            Some(next) if next > now => next,
+            _ => now,
+        };
+        let nanos = self.carry +
+            bytes as u64 * 1_000_000_000 / self.rate;
+        self.next = Some(start + Duration::from_millis(nanos / 1_000_000));
+        self.carry = nanos % 1_000_000;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use rotor::{Time, EventSet};
+    use testing::{pair, MockSocket, StreamDriver};
+    use testing::echo::Echo;
+    use {StreamOptions};
+    use super::RateLimit;
+
+    #[test]
+    fn small_chunks() {
+        let now = Time::zero();
+        let mut limit = RateLimit::new(1_000_000);
+        assert_eq!(limit.allowed(now), Ok(100_000));
+        // 100 bytes take 0.1 ms, so 10 of them fit in a millisecond
+        for _ in 0..10 {
+            assert!(limit.allowed(now).is_ok());
+            limit.transferred(now, 100);
+        }
+        let next = now + Duration::from_millis(1);
+        assert_eq!(limit.allowed(now), Err(next));
+        assert!(limit.allowed(next).is_ok());
+    }
+
+    #[test]
+    fn burst() {
+        let now = Time::zero();
+        let mut limit = RateLimit::new(1000);
+        assert_eq!(limit.allowed(now), Ok(100));
+        limit.transferred(now, 100);
+        let next = now + Duration::from_millis(100);
+        assert_eq!(limit.allowed(now), Err(next));
+        // Idle time is not accumulated
+        let later = now + Duration::new(10, 0);
+        limit.transferred(later, 100);
+        assert_eq!(limit.allowed(later),
+                   Err(later + Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn write_rate() {
+        let (sock, peer) = pair();
+        // 50 bytes each 100 milliseconds
+        let options = StreamOptions::new().write_rate(500);
+        let mut driver = StreamDriver::<Echo<MockSocket>>::with_options(
+            sock, (), options, ());
+        let line = [b'x'; 49].iter().chain(b"\n").cloned()
+            .collect::<Vec<_>>();
+        peer.push(&line);
+        peer.push(&line);
+        peer.push(&line);
+        driver.ready(EventSet::readable());
+        assert_eq!(peer.take_output().len(), 50);
+        driver.advance(Duration::from_millis(50));
+        assert_eq!(peer.take_output().len(), 0);
+        driver.advance(Duration::from_millis(50));
+        assert_eq!(peer.take_output().len(), 50);
+        driver.advance(Duration::from_millis(100));
+        assert_eq!(peer.take_output(), line);
+        assert!(!driver.is_closed());
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_read_rate() {
+        StreamOptions::new().read_rate(0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_write_rate() {
+        StreamOptions::new().write_rate(0);
+    }
+}
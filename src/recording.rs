@@ -0,0 +1,499 @@
+use std::io;
+use std::fs::File;
+use std::path::Path;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+
+use rotor::mio::{Evented, EventSet, PollOpt, Selector, Token};
+
+use testing::{pair, MockSocket, MockPeer, ReadEvent, WriteEvent};
+use testing::{StreamDriver};
+use {Protocol, SocketError};
+
+
+const MAGIC: &'static [u8] = b"rotor-stream recording v1\n";
+
+// Error kinds which can be stored in a file, everything else is `Other`
+const ERROR_KINDS: [io::ErrorKind; 12] = [
+    io::ErrorKind::Other,
+    io::ErrorKind::ConnectionReset,
+    io::ErrorKind::ConnectionAborted,
+    io::ErrorKind::ConnectionRefused,
+    io::ErrorKind::BrokenPipe,
+    io::ErrorKind::NotConnected,
+    io::ErrorKind::TimedOut,
+    io::ErrorKind::Interrupted,
+    io::ErrorKind::WriteZero,
+    io::ErrorKind::UnexpectedEof,
+    io::ErrorKind::InvalidInput,
+    io::ErrorKind::InvalidData,
+];
+
+
+/// A single I/O operation on the `RecordingSocket`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IoEvent {
+    /// Readiness events passed to the stream (before the I/O they cause)
+    ///
+    /// Recorded only if enabled by `StreamOptions::record_events`
+    Ready(EventSet),
+    /// Data returned by a single `read()`
+    Read(Vec<u8>),
+    /// `read()` returned zero bytes
+    ReadEof,
+    /// `read()` returned `WouldBlock`
+    ReadWouldBlock,
+    /// `read()` returned an error
+    ReadError(io::ErrorKind),
+    /// Data accepted by a single `write()` (may be shorter than buffer)
+    Write(Vec<u8>),
+    /// `write()` returned `WouldBlock`
+    WriteWouldBlock,
+    /// `write()` returned an error
+    WriteError(io::ErrorKind),
+}
+
+/// A log of the I/O operations of the `RecordingSocket`
+///
+/// This is a shared handle, so you can keep a clone and save it when
+/// something goes wrong, while socket itself is owned by the state machine.
+#[derive(Debug, Clone)]
+pub struct Recording(Arc<Mutex<Vec<IoEvent>>>);
+
+/// A socket wrapper that records all reads and writes
+///
+/// Every `read()` and `write()` call is recorded with exact data and
+/// result, so when replayed, the protocol observes exactly the same
+/// chunking of input. Readiness events of the `Stream` are recorded too if
+/// the recording is passed to `StreamOptions::record_events`, see
+/// `Recording::replay_driver`.
+///
+/// Note: the recording grows unbounded, so in production you should
+/// record only selected connections or drop the recording periodically.
+#[derive(Debug)]
+pub struct RecordingSocket<S> {
+    sock: S,
+    recording: Recording,
+}
+
+impl Default for Recording {
+    fn default() -> Recording {
+        Recording::new()
+    }
+}
+
+impl Recording {
+    pub fn new() -> Recording {
+        Recording(Arc::new(Mutex::new(Vec::new())))
+    }
+    fn push(&self, event: IoEvent) {
+        self.0.lock().unwrap().push(event);
+    }
+    /// Returns a copy of all the recorded events
+    pub fn events(&self) -> Vec<IoEvent> {
+        self.0.lock().unwrap().clone()
+    }
+    /// Forget all the recorded events
+    pub fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+    /// Returns all the data written to the socket
+    ///
+    /// This is useful to compare with `MockPeer::output()` after replay
+    pub fn output(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+        for event in self.0.lock().unwrap().iter() {
+            if let IoEvent::Write(ref data) = *event {
+                result.extend_from_slice(data);
+            }
+        }
+        result
+    }
+    /// Create a mock socket that reproduces the recording
+    ///
+    /// Reads return exactly the same chunks in the same order, interleaved
+    /// with `WouldBlock` and errors as recorded. Writes accept the same
+    /// number of bytes as original socket did. The data written during
+    /// replay may be fetched by `MockPeer::output()`.
+    pub fn replay(&self) -> (MockSocket, MockPeer) {
+        let (sock, peer) = pair();
+        for event in self.0.lock().unwrap().iter() {
+            match *event {
+                IoEvent::Read(ref data) => {
+                    peer.push_event(ReadEvent::Data(data.clone()));
+                }
+                IoEvent::ReadEof => peer.push_eof(),
+                IoEvent::ReadWouldBlock => peer.push_would_block(),
+                IoEvent::ReadError(kind) => {
+                    peer.push_read_error(replayed_error(kind));
+                }
+                IoEvent::Write(ref data) => {
+                    peer.push_write_event(WriteEvent::Accept(data.len()));
+                }
+                IoEvent::WriteWouldBlock => {
+                    peer.push_write_event(WriteEvent::WouldBlock);
+                }
+                IoEvent::WriteError(kind) => {
+                    peer.push_write_event(
+                        WriteEvent::Error(replayed_error(kind)));
+                }
+                // Used by `replay_driver`
+                IoEvent::Ready(_) => {}
+            }
+        }
+        // nothing is recorded after this point, so original socket would
+        // block anyway
+        peer.block_writes(true);
+        (sock, peer)
+    }
+    /// Create a driver of the protocol that reproduces the recording
+    ///
+    /// Unlike `replay()` this also feeds the recorded readiness events to
+    /// the stream in the same order, so the bugs depending on edge
+    /// triggered notifications are reproduced too. Timers and wakeups are
+    /// not recorded, use the driver to emulate them.
+    pub fn replay_driver<P>(&self, seed: P::Seed, context: P::Context)
+        -> (StreamDriver<P>, MockPeer)
+        where P: Protocol<Socket=MockSocket>
+    {
+        let (sock, peer) = self.replay();
+        let mut driver = StreamDriver::new(sock, seed, context);
+        for event in self.events() {
+            if let IoEvent::Ready(events) = event {
+                driver.ready(events);
+            }
+        }
+        (driver, peer)
+    }
+    /// Write the recording in binary format
+    ///
+    /// The format is a magic line followed by a sequence of records.
+    /// Each record is a tag byte, a 32bit big endian length and the data.
+    pub fn write_to<W: Write>(&self, dest: &mut W) -> io::Result<()> {
+        try!(dest.write_all(MAGIC));
+        for event in self.0.lock().unwrap().iter() {
+            let code;
+            let (tag, data): (u8, &[u8]) = match *event {
+                IoEvent::Ready(events) => {
+                    code = [events_code(events)];
+                    (b'E', &code)
+                }
+                IoEvent::Read(ref data) => (b'r', data),
+                IoEvent::ReadEof => (b'e', b""),
+                IoEvent::ReadWouldBlock => (b'b', b""),
+                IoEvent::ReadError(kind) => {
+                    code = [error_code(kind)];
+                    (b'x', &code)
+                }
+                IoEvent::Write(ref data) => (b'w', data),
+                IoEvent::WriteWouldBlock => (b'B', b""),
+                IoEvent::WriteError(kind) => {
+                    code = [error_code(kind)];
+                    (b'X', &code)
+                }
+            };
+            let len = data.len() as u32;
+            try!(dest.write_all(&[tag,
+                (len >> 24) as u8, (len >> 16) as u8,
+                (len >> 8) as u8, len as u8]));
+            try!(dest.write_all(data));
+        }
+        Ok(())
+    }
+    /// Read the recording written by `write_to`
+    pub fn read_from<R: Read>(src: &mut R) -> io::Result<Recording> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let mut magic = vec![0u8; MAGIC.len()];
+        try!(src.read_exact(&mut magic));
+        if &magic[..] != MAGIC {
+            return Err(invalid("not a rotor-stream recording"));
+        }
+        let mut events = Vec::new();
+        loop {
+            let mut header = [0u8; 5];
+            match src.read(&mut header[..1]) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                    continue;
+                }
+                Err(e) => return Err(e),
+            }
+            try!(src.read_exact(&mut header[1..]));
+            let len = (header[1] as usize) << 24 | (header[2] as usize) << 16
+                    | (header[3] as usize) << 8 | header[4] as usize;
+            // Don't trust the length to allocate the buffer, the file may
+            // be truncated or corrupt
+            let mut data = Vec::new();
+            try!(src.by_ref().take(len as u64).read_to_end(&mut data));
+            if data.len() != len {
+                return Err(invalid("truncated recording"));
+            }
+            events.push(match header[0] {
+                b'E' => IoEvent::Ready(try!(events_set(&data))),
+                b'r' => IoEvent::Read(data),
+                b'e' => IoEvent::ReadEof,
+                b'b' => IoEvent::ReadWouldBlock,
+                b'x' => IoEvent::ReadError(try!(error_kind(&data))),
+                b'w' => IoEvent::Write(data),
+                b'B' => IoEvent::WriteWouldBlock,
+                b'X' => IoEvent::WriteError(try!(error_kind(&data))),
+                _ => return Err(invalid("unknown record in recording")),
+            });
+        }
+        Ok(Recording(Arc::new(Mutex::new(events))))
+    }
+    /// Save the recording to a file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = try!(File::create(path));
+        self.write_to(&mut file)
+    }
+    /// Load the recording from a file
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Recording> {
+        let mut file = try!(File::open(path));
+        Recording::read_from(&mut file)
+    }
+}
+
+/// Appends readiness events, see `StreamOptions::record_events`
+pub fn record_ready(recording: &Recording, events: EventSet) {
+    recording.push(IoEvent::Ready(events));
+}
+
+fn events_code(events: EventSet) -> u8 {
+    (events.is_readable() as u8) | (events.is_writable() as u8) << 1 |
+    (events.is_error() as u8) << 2 | (events.is_hup() as u8) << 3
+}
+
+fn events_set(data: &[u8]) -> io::Result<EventSet> {
+    match data.first() {
+        Some(&code) if data.len() == 1 && code < 16 => {
+            let mut events = EventSet::none();
+            for &(bit, set) in &[(1, EventSet::readable()),
+                                 (2, EventSet::writable()),
+                                 (4, EventSet::error()),
+                                 (8, EventSet::hup())]
+            {
+                if code & bit != 0 {
+                    events.insert(set);
+                }
+            }
+            Ok(events)
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                "invalid events in recording")),
+    }
+}
+
+fn replayed_error(kind: io::ErrorKind) -> io::Error {
+    io::Error::new(kind, "replayed error")
+}
+
+fn error_code(kind: io::ErrorKind) -> u8 {
+    ERROR_KINDS.iter().position(|&k| k == kind).unwrap_or(0) as u8
+}
+
+fn error_kind(data: &[u8]) -> io::Result<io::ErrorKind> {
+    match data.first().and_then(|&x| ERROR_KINDS.get(x as usize)) {
+        Some(&kind) if data.len() == 1 => Ok(kind),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                "invalid error code in recording")),
+    }
+}
+
+impl<S> RecordingSocket<S> {
+    /// Wrap the socket appending events to the `recording`
+    pub fn new(sock: S, recording: &Recording) -> RecordingSocket<S> {
+        RecordingSocket {
+            sock: sock,
+            recording: recording.clone(),
+        }
+    }
+    /// Returns the recording of the socket
+    pub fn recording(&self) -> &Recording {
+        &self.recording
+    }
+    /// Returns a reference to the underlying socket
+    pub fn get_ref(&self) -> &S {
+        &self.sock
+    }
+    /// Returns a mutable reference to the underlying socket
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.sock
+    }
+    /// Unwraps the underlying socket
+    pub fn into_inner(self) -> S {
+        self.sock
+    }
+}
+
+impl<S: Read> Read for RecordingSocket<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = self.sock.read(buf);
+        self.recording.push(match result {
+            Ok(0) => IoEvent::ReadEof,
+            Ok(n) => IoEvent::Read(buf[..n].to_vec()),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                IoEvent::ReadWouldBlock
+            }
+            Err(ref e) => IoEvent::ReadError(e.kind()),
+        });
+        result
+    }
+}
+
+impl<S: Write> Write for RecordingSocket<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let result = self.sock.write(buf);
+        self.recording.push(match result {
+            Ok(n) => IoEvent::Write(buf[..n].to_vec()),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                IoEvent::WriteWouldBlock
+            }
+            Err(ref e) => IoEvent::WriteError(e.kind()),
+        });
+        result
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.sock.flush()
+    }
+}
+
+impl<S: Evented> Evented for RecordingSocket<S> {
+    fn register(&self, selector: &mut Selector, token: Token,
+        interest: EventSet, opts: PollOpt)
+        -> io::Result<()>
+    {
+        self.sock.register(selector, token, interest, opts)
+    }
+    fn reregister(&self, selector: &mut Selector, token: Token,
+        interest: EventSet, opts: PollOpt)
+        -> io::Result<()>
+    {
+        self.sock.reregister(selector, token, interest, opts)
+    }
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.sock.deregister(selector)
+    }
+}
+
+impl<S: SocketError> SocketError for RecordingSocket<S> {
+    fn take_socket_error(&self) -> io::Result<()> {
+        self.sock.take_socket_error()
+    }
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.sock.shutdown_write()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write, ErrorKind};
+    use rotor::EventSet;
+    use testing::{pair, WriteEvent, MockSocket, StreamDriver};
+    use testing::echo::Echo;
+    use {StreamOptions};
+    use super::{Recording, RecordingSocket, IoEvent, record_ready};
+
+    fn record() -> Recording {
+        let (sock, peer) = pair();
+        let rec = Recording::new();
+        let mut sock = RecordingSocket::new(sock, &rec);
+        peer.push(b"hello");
+        peer.push(b"world");
+        peer.push_read_error(ErrorKind::ConnectionReset.into());
+        peer.push_write_event(WriteEvent::Accept(2));
+        peer.push_write_event(WriteEvent::WouldBlock);
+        let mut buf = [0u8; 3];
+        record_ready(&rec, EventSet::readable() | EventSet::hup());
+        assert_eq!(sock.read(&mut buf).unwrap(), 3);
+        assert_eq!(sock.write(b"hey").unwrap(), 2);
+        sock.write(b"y").unwrap_err();
+        assert_eq!(sock.read(&mut buf).unwrap(), 2);
+        assert_eq!(sock.read(&mut buf).unwrap(), 3);
+        assert_eq!(sock.read(&mut buf).unwrap(), 2);
+        sock.read(&mut buf).unwrap_err();
+        sock.read(&mut buf).unwrap_err();
+        assert_eq!(sock.write(b"y").unwrap(), 1);
+        rec
+    }
+
+    #[test]
+    fn events() {
+        assert_eq!(record().events(), vec![
+            IoEvent::Ready(EventSet::readable() | EventSet::hup()),
+            IoEvent::Read(b"hel".to_vec()),
+            IoEvent::Write(b"he".to_vec()),
+            IoEvent::WriteWouldBlock,
+            IoEvent::Read(b"lo".to_vec()),
+            IoEvent::Read(b"wor".to_vec()),
+            IoEvent::Read(b"ld".to_vec()),
+            IoEvent::ReadError(ErrorKind::ConnectionReset),
+            IoEvent::ReadWouldBlock,
+            IoEvent::Write(b"y".to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn save_load() {
+        let rec = record();
+        let mut buf = Vec::new();
+        rec.write_to(&mut buf).unwrap();
+        let loaded = Recording::read_from(&mut &buf[..]).unwrap();
+        assert_eq!(loaded.events(), rec.events());
+        assert!(Recording::read_from(&mut &buf[1..]).is_err());
+        let len = buf.len();
+        assert_eq!(Recording::read_from(&mut &buf[..len-1]).unwrap_err()
+                   .kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn huge_length() {
+        let mut buf = Vec::new();
+        Recording::new().write_to(&mut buf).unwrap();
+        buf.extend(b"r\xff\xff\xff\xffhello");
+        assert_eq!(Recording::read_from(&mut &buf[..]).unwrap_err().kind(),
+                   ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn replay() {
+        let rec = record();
+        let (mut sock, peer) = rec.replay();
+        let mut buf = [0u8; 100];
+        assert_eq!(sock.read(&mut buf).unwrap(), 3);
+        assert_eq!(sock.read(&mut buf).unwrap(), 2);
+        assert_eq!(sock.read(&mut buf).unwrap(), 3);
+        assert_eq!(sock.read(&mut buf).unwrap(), 2);
+        assert_eq!(sock.read(&mut buf).unwrap_err().kind(),
+                   ErrorKind::ConnectionReset);
+        assert_eq!(sock.write(b"hey").unwrap(), 2);
+        assert_eq!(sock.write(b"y").unwrap_err().kind(),
+                   ErrorKind::WouldBlock);
+        assert_eq!(sock.write(b"yyy").unwrap(), 1);
+        assert_eq!(sock.write(b"yy").unwrap_err().kind(),
+                   ErrorKind::WouldBlock);
+        assert_eq!(peer.output(), rec.output());
+    }
+
+    #[test]
+    fn replay_driver() {
+        let (sock, peer) = pair();
+        let rec = Recording::new();
+        let options = StreamOptions::new().record_events(&rec);
+        let mut driver = StreamDriver::<Echo<RecordingSocket<MockSocket>>>
+            ::with_options(RecordingSocket::new(sock, &rec), (), options, ());
+        peer.push(b"hello\n");
+        driver.ready(EventSet::readable());
+        // Data without a readiness event is not noticed by the stream
+        peer.push(b"world\n");
+        driver.ready(EventSet::writable());
+        assert_eq!(peer.output(), b"hello\n");
+        driver.ready(EventSet::readable());
+        assert_eq!(peer.output(), b"hello\nworld\n");
+
+        let (_driver, replayed) = rec.replay_driver::<Echo<MockSocket>>(
+            (), ());
+        assert_eq!(replayed.output(), b"hello\nworld\n");
+    }
+}
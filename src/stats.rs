@@ -0,0 +1,79 @@
+use std::cmp::max;
+use std::time::{Duration, Instant};
+
+use {Stats};
+
+
+impl Stats {
+    /// Statistics of the connection established right now
+    pub fn new() -> Stats {
+        Stats {
+            connected_since: Some(Instant::now()),
+            .. Stats::empty()
+        }
+    }
+    /// Statistics with all zeros and no connection
+    pub fn empty() -> Stats {
+        Stats {
+            bytes_read: 0,
+            bytes_written: 0,
+            read_calls: 0,
+            write_calls: 0,
+            read_would_block: 0,
+            write_would_block: 0,
+            max_inbuf: 0,
+            max_outbuf: 0,
+            connected_since: None,
+            connected_before: Duration::new(0, 0),
+        }
+    }
+    /// Total time connection(s) are established
+    pub fn connected_time(&self) -> Duration {
+        match self.connected_since {
+            Some(start) => self.connected_before + start.elapsed(),
+            None => self.connected_before,
+        }
+    }
+    /// Add the statistics of the closed connection
+    ///
+    /// Counters are summed up and maximums are combined. The connection time
+    /// of `other` is frozen, it doesn't grow any more in the sum.
+    pub fn add(&mut self, other: &Stats) {
+        self.bytes_read += other.bytes_read;
+        self.bytes_written += other.bytes_written;
+        self.read_calls += other.read_calls;
+        self.write_calls += other.write_calls;
+        self.read_would_block += other.read_would_block;
+        self.write_would_block += other.write_would_block;
+        self.max_inbuf = max(self.max_inbuf, other.max_inbuf);
+        self.max_outbuf = max(self.max_outbuf, other.max_outbuf);
+        self.connected_before = self.connected_before
+            + other.connected_time();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rotor::EventSet;
+    use testing::{pair, MockSocket, StreamDriver, WriteEvent};
+    use testing::echo::Echo;
+
+    #[test]
+    fn driver_stats() {
+        let (sock, peer) = pair();
+        let mut driver = StreamDriver::<Echo<MockSocket>>::new(sock, (), ());
+        peer.push(b"hello\n");
+        peer.push(b"world\n");
+        peer.push_write_event(WriteEvent::Accept(3));
+        driver.ready(EventSet::readable());
+        let transport = driver.transport().unwrap();
+        let stats = transport.stats();
+        assert_eq!(stats.bytes_read, 12);
+        assert_eq!(stats.read_calls, 3);
+        assert_eq!(stats.read_would_block, 1);
+        assert_eq!(stats.bytes_written, 12);
+        assert_eq!(stats.write_calls, 3);
+        assert_eq!(stats.max_inbuf, 6);
+        assert_eq!(stats.max_outbuf, 6);
+    }
+}
@@ -1,15 +1,32 @@
 use std::io;
+use std::usize;
+use std::io::Read;
+use std::time::Duration;
+use std::cmp::{min, max};
 use std::error::Error;
 use std::io::ErrorKind::{WouldBlock, BrokenPipe, WriteZero, ConnectionReset};
+use std::io::ErrorKind::{Other};
 
 use rotor::{Response, Scope, Machine, EventSet, PollOpt, Time};
 use rotor::void::{Void, unreachable};
 
 use substr::find_substr;
 use extensions::{ScopeExt, ResponseExt};
+use metrics::report;
+use output::OutputQueue;
+use rate::RateLimit;
+use intention::Deadline;
 use {Expectation, Protocol, StreamSocket, Stream, StreamImpl};
 use {Buf, Transport, Accepted, Exception, Intent};
-use {ProtocolStop, SocketError};
+use {ProtocolStop, SocketError, Stats, StreamOptions, MAX_BUF_SIZE};
+use recording::record_ready;
+use testing::Recording;
+use {Drain, PROXY_HEADER_TIMEOUT};
+
+
+// This is the minimum allocation of `netbuf::Buf`, there is no sense to
+// shrink buffers which are smaller
+const MIN_SHRINK_CAPACITY: usize = 16384;
 
 
 #[derive(Debug)]
@@ -20,11 +37,49 @@ enum IoOp {
     Error(io::Error),
 }
 
-fn to_result<P: Protocol>(intent: Intent<P>)
+fn exception<P: Protocol>(fsm: P, transport: &mut Transport<P::Socket>,
+    reason: Exception, scope: &mut Scope<P::Context>)
+    -> Intent<P>
+{
+    report(|m| m.exception(&reason));
+    fsm.exception(transport, reason, scope)
+}
+
+fn fatal<P: Protocol>(fsm: P, reason: Exception,
+    scope: &mut Scope<P::Context>)
+    -> Option<Box<Error>>
+{
+    report(|m| m.fatal(&reason));
+    fsm.fatal(reason, scope)
+}
+
+fn shrink(buf: &mut Buf, threshold: usize) {
+    if buf.capacity() > max(threshold, MIN_SHRINK_CAPACITY) &&
+        buf.len() < buf.capacity() / 2
+    {
+        let mut new = Buf::new();
+        new.extend(&buf[..]);
+        *buf = new;
+    }
+}
+
+fn write_max<S: StreamSocket>(buf: &mut Buf, sock: &mut S, max: usize)
+    -> io::Result<usize>
+{
+    if buf.len() <= max {
+        return buf.write_to(sock);
+    }
+    let bytes = try!(sock.write(&buf[..max]));
+    buf.consume(bytes);
+    Ok(bytes)
+}
+
+// The `previous` deadline is used if the intent keeps it
+fn to_result<P: Protocol>(intent: Intent<P>, previous: Option<Time>)
     -> Result<(P, Expectation, Option<Time>), Option<Box<Error>>>
 {
     match intent.0 {
-        Ok(x) => Ok((x, intent.1, intent.2)),
+        Ok(x) => Ok((x, intent.1, intent.2.resolve(previous))),
         Err(e) => Err(e),
     }
 }
@@ -35,91 +90,118 @@ impl<S: StreamSocket> StreamImpl<S> {
             sock: &mut self.socket,
             inbuf: &mut self.inbuf,
             outbuf: &mut self.outbuf,
+            outqueue: &mut self.outqueue,
+            proxy: self.proxy.as_ref().map(|x| &**x),
+            stats: &self.stats,
+            options: &self.options,
+            deadline: self.deadline,
         }
     }
-    fn action<M>(self, intent: Intent<M>,
-        scope: &mut Scope<M::Context>)
-        -> Response<Stream<M>, Void>
-        where M: Protocol<Socket=S>
-    {
-        match self._action(intent, scope) {
-            Ok(stream) => {
-                let dline = stream.deadline;
-                Response::ok(stream).deadline_opt(dline)
-            }
-            Err(Some(e)) => Response::error(e),
-            Err(None) => Response::done(),
-        }
-    }
-    fn _action<P>(mut self, intent: Intent<P>,
+    fn _action<P>(&mut self, intent: Intent<P>,
         scope: &mut Scope<P::Context>)
-        -> Result<Stream<P>, Option<Box<Error>>>
+        -> Result<(P, Expectation, Option<Time>), Option<Box<Error>>>
         where P: Protocol<Socket=S>
     {
         use Expectation::*;
-        let mut intent = try!(to_result(intent));
-        let mut can_write = match self.write() {
+        let mut intent = try!(to_result(intent, self.deadline));
+        let now = scope.now();
+        self.resume = None;
+        // Budgets are per event
+        let read_limit = self.stats.bytes_read
+            .saturating_add(self.options.read_budget as u64);
+        let mut callbacks = 0;
+        let mut can_write = match self.write(now) {
             IoOp::Done => true,
             IoOp::NoOp => false,
             IoOp::Eos => {
                 self.outbuf.remove_range(..);
-                return Err(intent.0.fatal(
+                return Err(fatal(intent.0,
                     Exception::WriteError(io::Error::new(
                         WriteZero, "failed to write whole buffer")),
                     scope));
             }
             IoOp::Error(e) => {
-                return Err(intent.0.fatal(
+                return Err(fatal(intent.0,
                     Exception::WriteError(e),
                     scope));
             }
         };
         'outer: loop {
+            // This is what `Transport::deadline()` returns in callbacks
+            self.deadline = intent.2;
             if can_write {
-                can_write = match self.write() {
+                can_write = match self.write(now) {
                     IoOp::Done => true,
                     IoOp::NoOp => false,
                     IoOp::Eos => {
                         self.outbuf.remove_range(..);
-                        return Err(intent.0.fatal(
+                        return Err(fatal(intent.0,
                             Exception::WriteError(io::Error::new(
                                 WriteZero, "failed to write whole buffer")),
                             scope));
                     }
                     IoOp::Error(e) => {
                         self.outbuf.remove_range(..);
-                        return Err(intent.0.fatal(
+                        return Err(fatal(intent.0,
                             Exception::WriteError(e),
                             scope));
                     }
                 };
             }
+            // Only the data which can't be written right now is limited
+            if !can_write && self.outbuf.len() + self.outqueue.buffered() >
+                self.options.max_output
+            {
+                self.outbuf.remove_range(..);
+                return Err(fatal(intent.0,
+                    Exception::WriteError(io::Error::new(
+                        Other, "output buffer limit exceeded")),
+                    scope));
+            }
             match intent.1 {
                 Bytes(num) => {
                     loop {
                         if self.inbuf.len() >= num {
+                            if callbacks >= self.options.callback_budget &&
+                                self.yield_now(scope)
+                            {
+                                return Ok(intent);
+                            }
+                            callbacks += 1;
                             intent = try!(to_result(intent.0.bytes_read(
                                 &mut self.transport(),
-                                num, scope)));
+                                num, scope), intent.2));
+                            continue 'outer;
+                        }
+                        if self.inbuf.len() >= self.options.max_input {
+                            intent = try!(to_result(exception(intent.0,
+                                &mut self.transport(),
+                                Exception::LimitReached,
+                                scope), intent.2));
                             continue 'outer;
                         }
-                        match self.read() {
+                        if self.stats.bytes_read >= read_limit &&
+                            self.yield_now(scope)
+                        {
+                            return Ok(intent);
+                        }
+                        match self.read(now) {
                             IoOp::Done => {}
                             IoOp::NoOp => {
-                                return Ok(Stream::compose(self, intent));
+                                return Ok(intent);
                             }
                             IoOp::Eos => {
-                                intent = try!(to_result(intent.0.exception(
+                                intent = try!(to_result(exception(intent.0,
                                     &mut self.transport(),
                                     Exception::EndOfStream,
-                                    scope)));
+                                    scope), intent.2));
                                 continue 'outer;
                             }
                             IoOp::Error(e) => {
-                                intent = try!(to_result(intent.0.exception(
+                                intent = try!(to_result(exception(intent.0,
                                     &mut self.transport(),
                                     Exception::ReadError(e),
-                                    scope)));
+                                    scope), intent.2));
                                 continue 'outer;
                             }
                         }
@@ -130,81 +212,208 @@ impl<S: StreamSocket> StreamImpl<S> {
                         if self.inbuf.len() > min {
                             let opt = find_substr(&self.inbuf[min..], delim);
                             if let Some(num) = opt {
+                                if callbacks >=
+                                    self.options.callback_budget &&
+                                    self.yield_now(scope)
+                                {
+                                    return Ok(intent);
+                                }
+                                callbacks += 1;
                                 intent = try!(to_result(intent.0.bytes_read(
                                     &mut self.transport(),
-                                    num, scope)));
+                                    min + num, scope), intent.2));
                                 continue 'outer;
                             }
                         }
-                        if self.inbuf.len() > max {
-                            intent = try!(to_result(intent.0.exception(
+                        if self.inbuf.len() > max ||
+                            self.inbuf.len() >= self.options.max_input
+                        {
+                            intent = try!(to_result(exception(intent.0,
                                 &mut self.transport(),
                                 Exception::LimitReached,
-                                scope)));
+                                scope), intent.2));
                             continue 'outer;
                         }
-                        match self.read() {
+                        if self.stats.bytes_read >= read_limit &&
+                            self.yield_now(scope)
+                        {
+                            return Ok(intent);
+                        }
+                        match self.read(now) {
                             IoOp::Done => {}
                             IoOp::NoOp => {
-                                return Ok(Stream::compose(self, intent));
+                                return Ok(intent);
                             }
                             IoOp::Eos => {
-                                intent = try!(to_result(intent.0.exception(
+                                intent = try!(to_result(exception(intent.0,
                                     &mut self.transport(),
                                     Exception::EndOfStream,
-                                    scope)));
+                                    scope), intent.2));
                                 continue 'outer;
                             }
                             IoOp::Error(e) => {
-                                intent = try!(to_result(intent.0.exception(
+                                intent = try!(to_result(exception(intent.0,
                                     &mut self.transport(),
                                     Exception::ReadError(e),
-                                    scope)));
+                                    scope), intent.2));
                                 continue 'outer;
                             }
                         }
                     }
                 }
                 Flush(num) => {
-                    if self.outbuf.len() <= num {
+                    if self.outqueue.len() + self.outbuf.len() as u64 <=
+                        num as u64
+                    {
+                        if callbacks >= self.options.callback_budget &&
+                            self.yield_now(scope)
+                        {
+                            return Ok(intent);
+                        }
+                        callbacks += 1;
                         intent = try!(to_result(intent.0.bytes_flushed(
-                            &mut self.transport(), scope)));
+                            &mut self.transport(), scope), intent.2));
                     } else {
-                        return Ok(Stream::compose(self, intent));
+                        return Ok(intent);
                     }
                 }
                 Sleep => {
-                    return Ok(Stream::compose(self, intent));
+                    return Ok(intent);
                 }
             }
         }
     }
+    // Schedules a wakeup to continue processing on the next iteration of
+    // the loop. Returns false if it's impossible, so processing should
+    // continue right now.
+    fn yield_now<C>(&mut self, scope: &mut Scope<C>) -> bool {
+        if self.yielded {
+            // Wakeup is already scheduled
+            return true;
+        }
+        match scope.notifier().wakeup() {
+            Ok(()) => {
+                self.yielded = true;
+                true
+            }
+            Err(e) => {
+                debug!("Can't schedule wakeup, ignoring budget: {:?}", e);
+                false
+            }
+        }
+    }
+    // Re-arms the idle timeout
+    fn transferred(&mut self, now: Time) {
+        if let Some(timeout) = self.options.idle_timeout {
+            self.idle = Some(now + timeout);
+        }
+    }
+    fn pause(&mut self, time: Time) {
+        self.resume = Some(match self.resume {
+            Some(x) if x < time => x,
+            _ => time,
+        });
+    }
+    fn shrink_buffers(&mut self) {
+        if let Some(threshold) = self.options.shrink_threshold {
+            shrink(&mut self.inbuf, threshold);
+            shrink(&mut self.outbuf, threshold);
+        }
+    }
     // Returns Ok(true) to if we have read something, does not loop for reading
     // because this might use whole memory, and we may parse and consume the
     // input instead of buffering it whole.
-    fn read(&mut self) -> IoOp {
-        match self.inbuf.read_from(&mut self.socket) {
+    fn read(&mut self, now: Time) -> IoOp {
+        if !self.readable {
+            return IoOp::NoOp;
+        }
+        // Never read more than `max_input`, the caller checks that buffer
+        // is not full yet
+        let mut room = self.options.max_input - self.inbuf.len();
+        if let Some(allowed) =
+            self.read_limit.as_ref().map(|x| x.allowed(now))
+        {
+            match allowed {
+                Ok(bytes) => room = min(room, bytes),
+                Err(time) => {
+                    self.pause(time);
+                    return IoOp::NoOp;
+                }
+            }
+        }
+        self.stats.read_calls += 1;
+        let mut socket = Read::take(&mut self.socket, room as u64);
+        match self.inbuf.read_from(&mut socket) {
             Ok(0) => IoOp::Eos,
-            Ok(_) => IoOp::Done,
+            Ok(bytes) => {
+                if let Some(ref mut limit) = self.read_limit {
+                    limit.transferred(now, bytes);
+                }
+                self.transferred(now);
+                report(|m| m.bytes_read(bytes));
+                self.stats.bytes_read += bytes as u64;
+                self.stats.max_inbuf = max(self.stats.max_inbuf,
+                                           self.inbuf.len());
+                IoOp::Done
+            }
             Err(ref e) if e.kind() == BrokenPipe
                        || e.kind() == ConnectionReset
             => return IoOp::Eos,
-            Err(ref e) if e.kind() == WouldBlock => IoOp::NoOp,
+            Err(ref e) if e.kind() == WouldBlock => {
+                self.readable = false;
+                self.stats.read_would_block += 1;
+                IoOp::NoOp
+            }
             Err(e) => IoOp::Error(e),
         }
     }
-    fn write(&mut self) -> IoOp {
+    fn write(&mut self, now: Time) -> IoOp {
+        self.stats.max_outbuf = max(self.stats.max_outbuf, self.outbuf.len());
         loop {
-            if self.outbuf.len() == 0 {
+            if self.outqueue.is_empty() && self.outbuf.len() == 0 {
                 return IoOp::Done;
             }
-            match self.outbuf.write_to(&mut self.socket) {
+            if !self.writable {
+                return IoOp::NoOp;
+            }
+            let mut max = usize::MAX;
+            if let Some(allowed) =
+                self.write_limit.as_ref().map(|x| x.allowed(now))
+            {
+                match allowed {
+                    Ok(bytes) => max = bytes,
+                    Err(time) => {
+                        self.pause(time);
+                        return IoOp::NoOp;
+                    }
+                }
+            }
+            let res = match self.outqueue.write_to(&mut self.outbuf,
+                                                   &mut self.socket, max)
+            {
+                Some(res) => res,
+                None => write_max(&mut self.outbuf, &mut self.socket, max),
+            };
+            self.stats.write_calls += 1;
+            match res {
                 Ok(0) => return IoOp::Eos,
-                Ok(_) => continue,
+                Ok(bytes) => {
+                    if let Some(ref mut limit) = self.write_limit {
+                        limit.transferred(now, bytes);
+                    }
+                    self.transferred(now);
+                    report(|m| m.bytes_written(bytes));
+                    self.stats.bytes_written += bytes as u64;
+                    continue;
+                }
                 Err(ref e) if e.kind() == BrokenPipe
                            || e.kind() == ConnectionReset
                 => return IoOp::Eos,
-                Err(ref e) if e.kind() == WouldBlock => return IoOp::NoOp,
+                Err(ref e) if e.kind() == WouldBlock => {
+                    self.writable = false;
+                    self.stats.write_would_block += 1;
+                    return IoOp::NoOp;
+                }
                 Err(e) => return IoOp::Error(e),
             }
         }
@@ -222,6 +431,157 @@ impl<P: Protocol> Accepted for Stream<P>
     {
         Self::new(sock, seed, scope)
     }
+    fn accepted_with_options(sock: P::Socket, seed: <P as Protocol>::Seed,
+        options: &StreamOptions, scope: &mut Scope<Self::Context>)
+        -> Response<Self, Void>
+    {
+        Self::with_options(sock, seed, options.clone(), scope)
+    }
+}
+
+impl StreamOptions {
+    /// Default options: no limits
+    pub fn new() -> StreamOptions {
+        StreamOptions {
+            max_input: MAX_BUF_SIZE,
+            output_high_water: MAX_BUF_SIZE,
+            max_output: MAX_BUF_SIZE,
+            shrink_threshold: None,
+            read_budget: usize::MAX,
+            callback_budget: usize::MAX,
+            read_rate: None,
+            write_rate: None,
+            idle_timeout: None,
+            drain: None,
+            proxy_header_timeout: Duration::from_millis(PROXY_HEADER_TIMEOUT),
+            recording: None,
+        }
+    }
+    /// Maximum number of bytes in the input buffer
+    ///
+    /// When the buffer is full, the stream stops reading and calls
+    /// `Protocol::exception` with `LimitReached`. This protects the server
+    /// from buffering a lot of data when protocol expects `Bytes(num)` with
+    /// a large number supplied by a peer.
+    pub fn max_input_buffer(mut self, bytes: usize) -> StreamOptions {
+        self.max_input = bytes;
+        self
+    }
+    /// Size of the output buffer when `Transport::output_is_full()`
+    /// starts to return true
+    ///
+    /// This is only a hint for the protocol, nothing is enforced.
+    pub fn output_high_water(mut self, bytes: usize) -> StreamOptions {
+        self.output_high_water = bytes;
+        self
+    }
+    /// Maximum number of bytes in the output buffer
+    ///
+    /// When protocol (or another thread) puts more data in the buffer than
+    /// the socket accepts without blocking, the buffer is discarded and
+    /// `Protocol::fatal` is called with `WriteError`. This protects the
+    /// server from slow clients which don't read responses.
+    pub fn max_output_buffer(mut self, bytes: usize) -> StreamOptions {
+        self.max_output = bytes;
+        self
+    }
+    /// Shrink buffers larger than `bytes` when the stream is idle
+    ///
+    /// Buffers grow to fit the largest message and never shrink while they
+    /// hold any data (empty buffers are always deallocated). With this
+    /// option, when the stream waits for input or sleeps, buffers with
+    /// capacity larger than `bytes` and mostly unused are reallocated to fit
+    /// the data. This reduces memory used by idle keep-alive connections at
+    /// the cost of copying.
+    pub fn shrink_buffers(mut self, bytes: usize) -> StreamOptions {
+        self.shrink_threshold = Some(bytes);
+        self
+    }
+    /// Maximum number of bytes read from the socket per event
+    ///
+    /// When the budget is exhausted the stream stops reading and continues
+    /// on the next iteration of the event loop, so a fast client can't
+    /// starve other connections of the same loop. The protocol doesn't
+    /// notice this (the `wakeup` used to continue is not passed to the
+    /// protocol).
+    pub fn read_budget(mut self, bytes: usize) -> StreamOptions {
+        self.read_budget = bytes;
+        self
+    }
+    /// Maximum number of `bytes_read` and `bytes_flushed` calls per event
+    ///
+    /// This is similar to `read_budget`, but also limits the processing of
+    /// the data which is already in the buffer (e.g. pipelined requests
+    /// received by a single read).
+    pub fn callback_budget(mut self, num: usize) -> StreamOptions {
+        self.callback_budget = num;
+        self
+    }
+    /// Limit reading to `bytes` per second
+    ///
+    /// When the limit is reached, reading is paused and continued when
+    /// the timer expires, this is transparent for the protocol. The data
+    /// is read by chunks of up to 1/10 of the rate.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` is zero
+    pub fn read_rate(mut self, bytes: u64) -> StreamOptions {
+        assert!(bytes > 0);
+        self.read_rate = Some(bytes);
+        self
+    }
+    /// Limit writing to `bytes` per second
+    ///
+    /// Works the same way as `read_rate()`. Note the data waiting to be
+    /// written is accounted by `max_output_buffer`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` is zero
+    pub fn write_rate(mut self, bytes: u64) -> StreamOptions {
+        assert!(bytes > 0);
+        self.write_rate = Some(bytes);
+        self
+    }
+    /// Call `Protocol::timeout` if no bytes were read or written for the
+    /// `timeout`
+    ///
+    /// Unlike the deadline of the `Intent`, which is set anew by every
+    /// callback of the protocol, this timer is re-armed only by the
+    /// successful reads and writes. It's also re-armed when it expires, so
+    /// unless the protocol closes the connection, `timeout` is called again
+    /// after the next idle period.
+    pub fn idle_timeout(mut self, timeout: Duration) -> StreamOptions {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+    /// The handle reported by `Transport::is_draining()`
+    ///
+    /// `Accept` sets this to the handle of `AcceptOptions::drain`.
+    pub fn drain(mut self, drain: &Drain) -> StreamOptions {
+        self.drain = Some(drain.clone());
+        self
+    }
+    /// Time to receive the PROXY protocol header
+    ///
+    /// Only used by `ProxyProtocolStream`, the connection is closed if the
+    /// header isn't received in time. Default is `PROXY_HEADER_TIMEOUT`.
+    pub fn proxy_header_timeout(mut self, timeout: Duration)
+        -> StreamOptions
+    {
+        self.proxy_header_timeout = timeout;
+        self
+    }
+    /// Append readiness events of the stream to the `recording`
+    ///
+    /// Use the same recording as for the `RecordingSocket`, so
+    /// `Recording::replay_driver` can reproduce the events in the same
+    /// order relative to reads and writes.
+    pub fn record_events(mut self, recording: &Recording) -> StreamOptions {
+        self.recording = Some(recording.clone());
+        self
+    }
 }
 
 impl<P: Protocol> Stream<P> {
@@ -235,6 +595,11 @@ impl<P: Protocol> Stream<P> {
             sock: &mut self.socket,
             inbuf: &mut self.inbuf,
             outbuf: &mut self.outbuf,
+            outqueue: &mut self.outqueue,
+            proxy: self.proxy.as_ref().map(|x| &**x),
+            stats: &self.stats,
+            options: &self.options,
+            deadline: self.deadline,
         }
     }
     /// Get a `Protocol` object for the stream
@@ -245,13 +610,29 @@ impl<P: Protocol> Stream<P> {
     pub fn protocol(&mut self) -> &mut P {
         &mut self.fsm
     }
+    /// Returns the deadline of the current intent of the protocol
+    pub fn deadline(&self) -> Option<Time> {
+        self.deadline
+    }
     fn decompose(self) -> (P, Expectation, Option<Time>, StreamImpl<P::Socket>)
     {
         (self.fsm, self.expectation, self.deadline, StreamImpl {
             socket: self.socket,
             connected: self.connected,
+            readable: self.readable,
+            writable: self.writable,
+            yielded: self.yielded,
+            read_limit: self.read_limit,
+            write_limit: self.write_limit,
+            resume: self.resume,
+            idle: self.idle,
+            deadline: self.deadline,
             inbuf: self.inbuf,
             outbuf: self.outbuf,
+            outqueue: self.outqueue,
+            proxy: self.proxy,
+            stats: self.stats,
+            options: self.options,
         })
     }
     fn compose(implem: StreamImpl<P::Socket>,
@@ -263,14 +644,31 @@ impl<P: Protocol> Stream<P> {
             socket: implem.socket,
             expectation: exp,
             connected: implem.connected,
+            readable: implem.readable,
+            writable: implem.writable,
+            yielded: implem.yielded,
+            read_limit: implem.read_limit,
+            write_limit: implem.write_limit,
+            resume: implem.resume,
+            idle: implem.idle,
             deadline: dline,
             inbuf: implem.inbuf,
             outbuf: implem.outbuf,
+            outqueue: implem.outqueue,
+            proxy: implem.proxy,
+            stats: implem.stats,
+            options: implem.options,
         }
     }
-    pub fn new(mut sock: P::Socket, seed: P::Seed,
+    pub fn new(sock: P::Socket, seed: P::Seed,
         scope: &mut Scope<P::Context>)
         -> Response<Self, Void>
+    {
+        Stream::with_options(sock, seed, StreamOptions::new(), scope)
+    }
+    pub fn with_options(sock: P::Socket, seed: P::Seed,
+        options: StreamOptions, scope: &mut Scope<P::Context>)
+        -> Response<Self, Void>
     {
         // Always register everything in edge-triggered mode.
         // This allows to never reregister socket.
@@ -282,61 +680,22 @@ impl<P: Protocol> Stream<P> {
         if let Err(e) = scope.register(&sock,
             EventSet::all(), PollOpt::edge())
         {
+            report(|m| m.connection_closed(&Stats::new()));
             // TODO(tailhook) wrap it to more clear error
             return Response::error(Box::new(e));
         }
-        let Intent(m, exp, dline) = P::create(seed, &mut sock, scope);
-        match m {
-            Err(None) => Response::error(Box::new(ProtocolStop)),
-            Err(Some(e)) => Response::error(e),
-            Ok(m) => {
-                Response::ok(Stream {
-                    socket: sock,
-                    expectation: exp,
-                    connected: false,
-                    deadline: dline,
-                    fsm: m,
-                    inbuf: Buf::new(),
-                    outbuf: Buf::new(),
-                }).deadline_opt(dline)
-            }
+        match create(sock, seed, false, options, scope) {
+            Ok(stream) => to_response(Ok(stream)),
+            Err(e) => Response::error(e),
         }
     }
-    pub fn connected(mut sock: P::Socket, seed: P::Seed,
+    pub fn connected(sock: P::Socket, seed: P::Seed,
         scope: &mut Scope<P::Context>)
         -> Response<Self, Void>
     {
-        // Always register everything in edge-triggered mode.
-        // This allows to never reregister socket.
-        //
-        // The no-reregister strategy is not a goal (although, it's expected
-        // to lower number of syscalls for many request-reply-like protocols)
-        // but it allows to have single source of truth for
-        // readable()/writable() mask (no duplication in kernel space)
-        //
-        // We reregister here, because we assume that higher level abstraction
-        // has the socket already registered (perhaps `Persistent` machine)
-        if let Err(e) = scope.reregister(&sock,
-            EventSet::all(), PollOpt::edge())
-        {
-            // TODO(tailhook) wrap it to more clear error
-            return Response::error(Box::new(e));
-        }
-        let Intent(m, exp, dline) = P::create(seed, &mut sock, scope);
-        match m {
-            Err(None) => Response::error(Box::new(ProtocolStop)),
-            Err(Some(e)) => Response::error(e),
-            Ok(m) => {
-                Response::ok(Stream {
-                    socket: sock,
-                    expectation: exp,
-                    connected: true,
-                    deadline: dline,
-                    fsm: m,
-                    inbuf: Buf::new(),
-                    outbuf: Buf::new(),
-                }).deadline_opt(dline)
-            }
+        match create_connected(sock, seed, scope) {
+            Ok(stream) => to_response(Ok(stream)),
+            Err(e) => Response::error(e),
         }
     }
 }
@@ -353,32 +712,7 @@ impl<P: Protocol> Machine for Stream<P>
     fn ready(self, events: EventSet, scope: &mut Scope<Self::Context>)
         -> Response<Self, Self::Seed>
     {
-        // TODO(tailhook) use `events` to optimize reading
-        let (fsm, exp, dline, mut imp) = self.decompose();
-        if events.is_hup() || events.is_error() {
-            match imp.socket.take_socket_error() {
-                Ok(()) => {
-                    match fsm.fatal(Exception::EndOfStream, scope) {
-                        Some(e) => return Response::error(e),
-                        None => return Response::done(),
-                    }
-                }
-                Err(e) => {
-                    let exception = if imp.connected {
-                        Exception::ReadError(e)
-                    } else {
-                        Exception::ConnectError(e)
-                    };
-                    match fsm.fatal(exception, scope) {
-                        Some(e) => return Response::error(e),
-                        None => return Response::done(),
-                    }
-                }
-            }
-        } else if !imp.connected {
-            imp.connected = true;
-        }
-        imp.action(Intent(Ok(fsm), exp, dline), scope)
+        to_response(ready(self, events, scope))
     }
     fn spawned(self, _scope: &mut Scope<Self::Context>)
         -> Response<Self, Self::Seed>
@@ -388,21 +722,403 @@ impl<P: Protocol> Machine for Stream<P>
     fn timeout(self, scope: &mut Scope<Self::Context>)
         -> Response<Self, Self::Seed>
     {
-        if scope.reached(self.deadline) {
-            let (fsm, _exp, _dline, mut imp) = self.decompose();
-            let res = fsm.timeout(&mut imp.transport(), scope);
-            imp.action(res, scope)
-        } else {
-            // TODO(tailhook) in rotor 0.6 should be no spurious timeouts
-            // anymore, but let's keep it until we remove Scope::timeout_ms()
-            Response::ok(self)
-        }
+        to_response(timer_expired(self, scope))
     }
     fn wakeup(self, scope: &mut Scope<Self::Context>)
         -> Response<Self, Self::Seed>
     {
-        let (fsm, _exp, _dline, mut imp) = self.decompose();
-        let res = fsm.wakeup(&mut imp.transport(), scope);
-        imp.action(res, scope)
+        to_response(wakeup(self, scope))
+    }
+}
+
+// The functions below are the state transitions of the `Stream`. They are
+// used by the `Machine` implementation, by `Persistent` and by
+// `testing::StreamDriver`
+
+/// The stream is closed, with an error or not. Statistics of the closed
+/// connection are returned too, because `Persistent` sums them up.
+pub type Closed = (Option<Box<Error>>, Stats);
+
+fn closed(err: Option<Box<Error>>, stats: Stats) -> Closed {
+    report(|m| m.connection_closed(&stats));
+    (err, stats)
+}
+
+/// Closes the stream without notifying the protocol
+///
+/// This is used by state machines which own several streams, like
+/// `Forward`, when one of them is closed.
+pub fn abort<P: Protocol>(stream: Stream<P>) -> Closed {
+    closed(None, stream.stats)
+}
+
+fn to_response<P: Protocol>(res: Result<Stream<P>, Closed>)
+    -> Response<Stream<P>, Void>
+{
+    match res {
+        Ok(stream) => {
+            let dline = timer(&stream);
+            Response::ok(stream).deadline_opt(dline)
+        }
+        Err((Some(e), _)) => Response::error(e),
+        Err((None, _)) => Response::done(),
+    }
+}
+
+/// Returns the time when `timer_expired` should be called
+pub fn timer<P: Protocol>(stream: &Stream<P>) -> Option<Time> {
+    [stream.deadline, stream.resume, stream.idle].iter()
+        .filter_map(|x| *x).min()
+}
+
+pub fn create<P: Protocol>(sock: P::Socket, seed: P::Seed,
+    connected: bool, options: StreamOptions, scope: &mut Scope<P::Context>)
+    -> Result<Stream<P>, Box<Error>>
+{
+    create_with_stats(sock, seed, connected, options, Stats::new(), scope)
+}
+
+/// Same as `create`, but the statistics of the connection start with
+/// `stats`, e.g. with the bytes of the PROXY protocol header
+///
+/// The connection is reported as closed if `Protocol::create` fails.
+pub fn create_with_stats<P: Protocol>(mut sock: P::Socket, seed: P::Seed,
+    connected: bool, options: StreamOptions, stats: Stats,
+    scope: &mut Scope<P::Context>)
+    -> Result<Stream<P>, Box<Error>>
+{
+    let Intent(m, exp, dline) = P::create(seed, &mut sock, scope);
+    match m {
+        Err(err) => {
+            report(|m| m.connection_closed(&stats));
+            Err(err.unwrap_or_else(|| Box::new(ProtocolStop)))
+        }
+        Ok(m) => {
+            Ok(Stream {
+                socket: sock,
+                expectation: exp,
+                connected: connected,
+                readable: true,
+                writable: true,
+                yielded: false,
+                read_limit: options.read_rate.map(RateLimit::new),
+                write_limit: options.write_rate.map(RateLimit::new),
+                resume: None,
+                idle: options.idle_timeout.map(|x| scope.now() + x),
+                deadline: dline.resolve(None),
+                fsm: m,
+                inbuf: Buf::new(),
+                outbuf: Buf::new(),
+                outqueue: OutputQueue::new(),
+                proxy: None,
+                stats: stats,
+                options: options,
+            })
+        }
+    }
+}
+
+pub fn create_connected<P: Protocol>(sock: P::Socket, seed: P::Seed,
+    scope: &mut Scope<P::Context>)
+    -> Result<Stream<P>, Box<Error>>
+{
+    // Always register everything in edge-triggered mode.
+    // This allows to never reregister socket.
+    //
+    // The no-reregister strategy is not a goal (although, it's expected
+    // to lower number of syscalls for many request-reply-like protocols)
+    // but it allows to have single source of truth for
+    // readable()/writable() mask (no duplication in kernel space)
+    //
+    // We reregister here, because we assume that higher level abstraction
+    // has the socket already registered (perhaps `Persistent` machine)
+    if let Err(e) = scope.reregister(&sock,
+        EventSet::all(), PollOpt::edge())
+    {
+        // TODO(tailhook) wrap it to more clear error
+        return Err(Box::new(e));
+    }
+    create(sock, seed, true, StreamOptions::new(), scope)
+}
+
+fn action<P: Protocol>(mut imp: StreamImpl<P::Socket>, intent: Intent<P>,
+    scope: &mut Scope<P::Context>)
+    -> Result<Stream<P>, Closed>
+{
+    match imp._action(intent, scope) {
+        Ok(intent) => {
+            // Don't shrink when flushing, it's likely the output buffer is
+            // going to be refilled as soon as it's flushed
+            match intent.1 {
+                Expectation::Flush(_) => {}
+                _ => imp.shrink_buffers(),
+            }
+            Ok(Stream::compose(imp, intent))
+        }
+        Err(e) => Err(closed(e, imp.stats)),
+    }
+}
+
+pub fn ready<P: Protocol>(stream: Stream<P>, events: EventSet,
+    scope: &mut Scope<P::Context>)
+    -> Result<Stream<P>, Closed>
+{
+    let (fsm, exp, dline, mut imp) = stream.decompose();
+    if let Some(ref recording) = imp.options.recording {
+        record_ready(recording, events);
+    }
+    if events.is_readable() {
+        imp.readable = true;
+    }
+    if events.is_writable() {
+        imp.writable = true;
+    }
+    if events.is_hup() || events.is_error() {
+        match imp.socket.take_socket_error() {
+            Ok(()) => {
+                let err = fatal(fsm, Exception::EndOfStream, scope);
+                return Err(closed(err, imp.stats));
+            }
+            Err(e) => {
+                let exception = if imp.connected {
+                    Exception::ReadError(e)
+                } else {
+                    Exception::ConnectError(e)
+                };
+                return Err(closed(fatal(fsm, exception, scope), imp.stats));
+            }
+        }
+    } else if !imp.connected {
+        imp.connected = true;
+    }
+    action(imp, Intent(Ok(fsm), exp, Deadline::Set(dline)), scope)
+}
+
+pub fn timeout<P: Protocol>(stream: Stream<P>,
+    scope: &mut Scope<P::Context>)
+    -> Result<Stream<P>, Closed>
+{
+    let (fsm, _exp, _dline, mut imp) = stream.decompose();
+    let res = fsm.timeout(&mut imp.transport(), scope);
+    action(imp, res, scope)
+}
+
+/// Dispatches the expiration of the `timer()`
+pub fn timer_expired<P: Protocol>(stream: Stream<P>,
+    scope: &mut Scope<P::Context>)
+    -> Result<Stream<P>, Closed>
+{
+    if scope.reached(stream.idle) {
+        idle_timeout(stream, scope)
+    } else if scope.reached(stream.deadline) {
+        timeout(stream, scope)
+    } else {
+        // Either I/O paused by rate limits should be resumed or it's a
+        // spurious timeout
+        //
+        // TODO(tailhook) in rotor 0.6 should be no spurious timeouts
+        // anymore, but let's keep it until we remove Scope::timeout_ms()
+        resume(stream, scope)
+    }
+}
+
+fn idle_timeout<P: Protocol>(stream: Stream<P>,
+    scope: &mut Scope<P::Context>)
+    -> Result<Stream<P>, Closed>
+{
+    let (fsm, _exp, _dline, mut imp) = stream.decompose();
+    imp.idle = imp.options.idle_timeout.map(|x| scope.now() + x);
+    let res = fsm.timeout(&mut imp.transport(), scope);
+    action(imp, res, scope)
+}
+
+/// Continues I/O paused by rate limits, without calling the protocol
+fn resume<P: Protocol>(stream: Stream<P>,
+    scope: &mut Scope<P::Context>)
+    -> Result<Stream<P>, Closed>
+{
+    let (fsm, exp, dline, imp) = stream.decompose();
+    action(imp, Intent(Ok(fsm), exp, Deadline::Set(dline)), scope)
+}
+
+pub fn wakeup<P: Protocol>(stream: Stream<P>,
+    scope: &mut Scope<P::Context>)
+    -> Result<Stream<P>, Closed>
+{
+    let (fsm, exp, dline, mut imp) = stream.decompose();
+    if imp.yielded {
+        // This is the wakeup scheduled when budget was exhausted
+        imp.yielded = false;
+        return action(imp, Intent(Ok(fsm), exp, Deadline::Set(dline)), scope);
+    }
+    let res = fsm.wakeup(&mut imp.transport(), scope);
+    action(imp, res, scope)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use rotor::EventSet;
+    use testing::{pair, MockSocket, StreamDriver};
+    use testing::echo::Echo;
+    use {StreamOptions};
+
+    #[test]
+    fn input_limit() {
+        let (sock, peer) = pair();
+        let options = StreamOptions::new().max_input_buffer(4);
+        let mut driver = StreamDriver::<Echo<MockSocket>>::with_options(
+            sock, (), options, ());
+        peer.push(b"ab\ncdefgh\n");
+        driver.ready(EventSet::readable());
+        assert!(driver.is_closed());
+        assert_eq!(peer.output(), b"ab\n");
+        assert!(driver.error().is_none());
+    }
+
+    #[test]
+    fn output_limit() {
+        let (sock, peer) = pair();
+        let options = StreamOptions::new()
+            .output_high_water(6).max_output_buffer(10);
+        let mut driver = StreamDriver::<Echo<MockSocket>>::with_options(
+            sock, (), options, ());
+        peer.block_writes(true);
+        driver.feed(b"abcd\n");
+        assert!(!driver.transport().unwrap().output_is_full());
+        driver.feed(b"efgh\n");
+        assert!(driver.transport().unwrap().output_is_full());
+        driver.feed(b"ijkl\n");
+        assert!(driver.is_closed());
+        assert_eq!(peer.output(), b"");
+    }
+
+    #[test]
+    fn output_limit_writable() {
+        let (sock, peer) = pair();
+        let options = StreamOptions::new().max_output_buffer(4);
+        let mut driver = StreamDriver::<Echo<MockSocket>>::with_options(
+            sock, (), options, ());
+        // A single write larger than the limit is fine if it's flushed
+        driver.feed(b"abcdefgh\n");
+        assert!(!driver.is_closed());
+        assert_eq!(peer.output(), b"abcdefgh\n");
+    }
+
+    #[test]
+    fn shrink_buffers() {
+        let mut data = vec![b'a'; 100000];
+        data.extend_from_slice(b"\nbc");
+        for &(threshold, shrunk) in &[(None, false), (Some(32768), true)] {
+            let (sock, peer) = pair();
+            let mut options = StreamOptions::new();
+            if let Some(threshold) = threshold {
+                options = options.shrink_buffers(threshold);
+            }
+            let mut driver = StreamDriver::<Echo<MockSocket>>::with_options(
+                sock, (), options, ());
+            driver.feed(&data);
+            assert_eq!(peer.output().len(), 100001);
+            let mut transport = driver.transport().unwrap();
+            assert_eq!(&transport.input()[..], b"bc");
+            assert_eq!(transport.input().capacity() <= 16384, shrunk);
+        }
+    }
+
+    #[test]
+    fn skip_would_block() {
+        let (sock, peer) = pair();
+        let mut driver = StreamDriver::<Echo<MockSocket>>::new(sock, (), ());
+        peer.block_writes(true);
+        peer.push(b"hello\n");
+        driver.ready(EventSet::readable());
+        // data, read would block, write would block
+        assert_eq!((peer.read_calls(), peer.write_calls()), (2, 1));
+        peer.push(b"world\n");
+        driver.ready(EventSet::readable());
+        // there was no writable event, so write would block anyway
+        assert_eq!((peer.read_calls(), peer.write_calls()), (4, 1));
+        peer.block_writes(false);
+        driver.ready(EventSet::writable());
+        // there was no readable event, so read would block anyway
+        assert_eq!((peer.read_calls(), peer.write_calls()), (4, 2));
+        assert_eq!(peer.take_output(), b"hello\nworld\n");
+        driver.wakeup();
+        assert_eq!((peer.read_calls(), peer.write_calls()), (4, 2));
+    }
+
+    #[test]
+    fn callback_budget() {
+        let (sock, peer) = pair();
+        let options = StreamOptions::new().callback_budget(1);
+        let mut driver = StreamDriver::<Echo<MockSocket>>::with_options(
+            sock, (), options, ());
+        peer.push(b"a\nb\nc\n");
+        driver.ready(EventSet::readable());
+        assert_eq!(peer.take_output(), b"a\n");
+        driver.wakeup();
+        assert_eq!(peer.take_output(), b"b\n");
+        driver.wakeup();
+        assert_eq!(peer.take_output(), b"c\n");
+        // Wakeups used for continuation are not passed to the protocol
+        assert!(!driver.is_shutdown());
+        driver.wakeup();
+        assert!(driver.is_shutdown());
+    }
+
+    #[test]
+    fn read_budget() {
+        let (sock, peer) = pair();
+        let options = StreamOptions::new().read_budget(4);
+        let mut driver = StreamDriver::<Echo<MockSocket>>::with_options(
+            sock, (), options, ());
+        peer.push(b"ab\n");
+        peer.push(b"cd\n");
+        peer.push(b"ef\n");
+        driver.ready(EventSet::readable());
+        assert_eq!(peer.take_output(), b"ab\ncd\n");
+        assert_eq!(peer.read_calls(), 2);
+        driver.wakeup();
+        assert_eq!(peer.take_output(), b"ef\n");
+        assert!(!driver.is_shutdown());
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let (sock, peer) = pair();
+        let options = StreamOptions::new()
+            .idle_timeout(Duration::from_millis(300));
+        let mut driver = StreamDriver::<Echo<MockSocket>>::with_options(
+            sock, (), options, ());
+        driver.advance(Duration::from_millis(200));
+        peer.push(b"hello\n");
+        driver.ready(EventSet::readable());
+        assert_eq!(peer.take_output(), b"hello\n");
+        // Re-armed by the transfer
+        driver.advance(Duration::from_millis(200));
+        assert!(!driver.is_closed());
+        // Echo closes the connection on timeout, the deadline is not
+        // reached yet
+        driver.advance(Duration::from_millis(100));
+        assert!(driver.is_closed());
+        assert!(driver.error().is_none());
+    }
+
+    #[test]
+    fn keep_deadline() {
+        let (sock, peer) = pair();
+        let mut driver = StreamDriver::<Echo<MockSocket>>::new(sock, (), ());
+        peer.push(b"hello\n");
+        driver.ready(EventSet::readable());
+        let dline = driver.deadline();
+        assert!(dline.is_some());
+        driver.advance(Duration::from_millis(600));
+        // Echo keeps the deadline on wakeup
+        driver.wakeup();
+        assert_eq!(driver.deadline(), dline);
+        assert_eq!(driver.transport().unwrap().deadline(), dline);
+        driver.advance(Duration::from_millis(400));
+        assert!(driver.is_closed());
     }
 }
@@ -0,0 +1,1131 @@
+//! Utilities for unit-testing protocols
+//!
+//! The main thing here is `MockSocket`, an in-memory socket which may be used
+//! as a `Protocol::Socket` in tests. Its counterpart, `MockPeer`, is used to
+//! script the input of the socket and inspect the output.
+//!
+//! Note: the socket can't be polled by the real event loop, registering it
+//! is a no-op. So you need to wake up the state machine manually after
+//! feeding the input.
+//!
+//! The `FaultySocket` wraps any socket and injects short reads and writes,
+//! spurious `WouldBlock` errors and connection resets, either scripted or
+//! pseudo-random (reproducible with the same seed).
+//!
+//! The `RecordingSocket` records all reads and writes of the real socket
+//! (including exact chunking of input), the `Recording` may be saved to a
+//! file and replayed later with `Recording::replay()`.
+//!
+//! The `StreamDriver` runs a `Protocol` without an event loop. It performs
+//! exactly the same state transitions as `Stream` does, but events are
+//! triggered by the test and time is virtual:
+//!
+//! ```ignore
+//! let (sock, peer) = pair();
+//! let mut driver = StreamDriver::<Echo>::new(sock, (), ());
+//! driver.feed(b"hello\n");
+//! assert_eq!(peer.take_output(), b"hello\n");
+//! driver.advance(Duration::new(10, 0));
+//! assert!(driver.is_closed());
+//! ```
+use std::io;
+use std::io::{Read, Write};
+use std::cmp::min;
+use std::error::Error;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use rotor::{Scope, Time, _scope, _LoopApi, _Notify};
+use rotor::mio::{Evented, EventSet, PollOpt, Selector, Token, Timeout};
+use rotor::mio::{EventLoop, Handler, Sender, TimerError};
+
+use stream;
+pub use recording::{Recording, RecordingSocket, IoEvent};
+use {SocketError, ActiveStream, Protocol, Stream, Expectation};
+use {Transport, Intent};
+use {Exception, StreamOptions};
+
+
+/// An event returned by the `read()` of the `MockSocket`
+#[derive(Debug)]
+pub enum ReadEvent {
+    /// A chunk of data
+    ///
+    /// Single `read()` never returns data from different chunks. So you can
+    /// reproduce exact read boundaries
+    Data(Vec<u8>),
+    /// `read()` returns `WouldBlock` error once
+    WouldBlock,
+    /// `read()` returns this error once
+    Error(io::Error),
+    /// End of stream, all subsequent `read()` calls return zero bytes
+    Eof,
+}
+
+/// An event for the `write()` of the `MockSocket`
+#[derive(Debug)]
+pub enum WriteEvent {
+    /// `write()` accepts no more than this number of bytes (short write)
+    Accept(usize),
+    /// `write()` returns `WouldBlock` error once
+    WouldBlock,
+    /// `write()` returns this error once
+    Error(io::Error),
+}
+
+#[derive(Debug)]
+struct State {
+    input: VecDeque<ReadEvent>,
+    eof: bool,
+    writes: VecDeque<WriteEvent>,
+    writes_blocked: bool,
+    output: Vec<u8>,
+    write_shutdown: bool,
+    socket_error: Option<io::Error>,
+    read_calls: usize,
+    write_calls: usize,
+    connected: bool,
+}
+
+/// In-memory socket for tests
+///
+/// Reads return the data scripted by `MockPeer`, when there is no scripted
+/// data `WouldBlock` error is returned. Writes append to the output buffer,
+/// which can be inspected by `MockPeer::output()`.
+#[derive(Debug)]
+pub struct MockSocket(Arc<Mutex<State>>);
+
+/// The other side of the `MockSocket`
+///
+/// The peer may be cloned and sent to other threads.
+#[derive(Debug, Clone)]
+pub struct MockPeer(Arc<Mutex<State>>);
+
+/// Create a mock socket and a peer object used to control it
+pub fn pair() -> (MockSocket, MockPeer) {
+    let state = Arc::new(Mutex::new(State {
+        input: VecDeque::new(),
+        eof: false,
+        writes: VecDeque::new(),
+        writes_blocked: false,
+        output: Vec::new(),
+        write_shutdown: false,
+        socket_error: None,
+        read_calls: 0,
+        write_calls: 0,
+        connected: true,
+    }));
+    (MockSocket(state.clone()), MockPeer(state))
+}
+
+impl MockPeer {
+    fn state(&self) -> MutexGuard<State> {
+        self.0.lock().unwrap()
+    }
+    /// Schedule an arbitrary read event
+    pub fn push_event(&self, event: ReadEvent) {
+        self.state().input.push_back(event);
+    }
+    /// Add a chunk of data to the input of the socket
+    pub fn push(&self, data: &[u8]) {
+        self.push_event(ReadEvent::Data(data.to_vec()));
+    }
+    /// Make next `read()` (after already scheduled data) return `WouldBlock`
+    pub fn push_would_block(&self) {
+        self.push_event(ReadEvent::WouldBlock);
+    }
+    /// Make next `read()` (after already scheduled data) return the error
+    pub fn push_read_error(&self, err: io::Error) {
+        self.push_event(ReadEvent::Error(err));
+    }
+    /// Close the input after all already scheduled data
+    pub fn push_eof(&self) {
+        self.push_event(ReadEvent::Eof);
+    }
+    /// Schedule an arbitrary write event
+    pub fn push_write_event(&self, event: WriteEvent) {
+        self.state().writes.push_back(event);
+    }
+    /// Makes all writes return `WouldBlock` if there are no scheduled events
+    ///
+    /// This emulates full socket buffer. By default all writes are accepted
+    /// in full.
+    pub fn block_writes(&self, value: bool) {
+        self.state().writes_blocked = value;
+    }
+    /// Set the value returned by `ActiveStream::is_connected`
+    ///
+    /// The socket is connected by default.
+    pub fn set_connected(&self, value: bool) {
+        self.state().connected = value;
+    }
+    /// Set the error returned by `SocketError::take_socket_error`
+    pub fn set_socket_error(&self, err: io::Error) {
+        self.state().socket_error = Some(err);
+    }
+    /// Returns the copy of the data written to the socket so far
+    pub fn output(&self) -> Vec<u8> {
+        self.state().output.clone()
+    }
+    /// Returns the data written to the socket and clears the output
+    pub fn take_output(&self) -> Vec<u8> {
+        let mut state = self.state();
+        let result = state.output.clone();
+        state.output.clear();
+        result
+    }
+    /// Returns true if `SocketError::shutdown_write` was called
+    pub fn is_write_shutdown(&self) -> bool {
+        self.state().write_shutdown
+    }
+    /// Returns true if all the scheduled input is read by the socket
+    pub fn input_consumed(&self) -> bool {
+        self.state().input.is_empty()
+    }
+    /// Number of `read()` calls on the socket
+    pub fn read_calls(&self) -> usize {
+        self.state().read_calls
+    }
+    /// Number of `write()` calls on the socket
+    pub fn write_calls(&self) -> usize {
+        self.state().write_calls
+    }
+}
+
+fn would_block() -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock, "mock socket would block")
+}
+
+impl Read for MockSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.0.lock().unwrap();
+        state.read_calls += 1;
+        if state.eof {
+            return Ok(0);
+        }
+        match state.input.pop_front() {
+            Some(ReadEvent::Data(mut chunk)) => {
+                let bytes = min(buf.len(), chunk.len());
+                buf[..bytes].copy_from_slice(&chunk[..bytes]);
+                if bytes < chunk.len() {
+                    let rest = chunk.split_off(bytes);
+                    state.input.push_front(ReadEvent::Data(rest));
+                }
+                Ok(bytes)
+            }
+            Some(ReadEvent::WouldBlock) => Err(would_block()),
+            Some(ReadEvent::Error(e)) => Err(e),
+            Some(ReadEvent::Eof) => {
+                state.eof = true;
+                Ok(0)
+            }
+            None => Err(would_block()),
+        }
+    }
+}
+
+impl Write for MockSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.0.lock().unwrap();
+        state.write_calls += 1;
+        if state.write_shutdown {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe,
+                "mock socket is shut down for writing"));
+        }
+        let bytes = match state.writes.pop_front() {
+            Some(WriteEvent::Accept(num)) => min(num, buf.len()),
+            Some(WriteEvent::WouldBlock) => return Err(would_block()),
+            Some(WriteEvent::Error(e)) => return Err(e),
+            None if state.writes_blocked => return Err(would_block()),
+            None => buf.len(),
+        };
+        state.output.extend_from_slice(&buf[..bytes]);
+        Ok(bytes)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Evented for MockSocket {
+    fn register(&self, _selector: &mut Selector, _token: Token,
+        _interest: EventSet, _opts: PollOpt)
+        -> io::Result<()>
+    {
+        Ok(())
+    }
+    fn reregister(&self, _selector: &mut Selector, _token: Token,
+        _interest: EventSet, _opts: PollOpt)
+        -> io::Result<()>
+    {
+        Ok(())
+    }
+    fn deregister(&self, _selector: &mut Selector) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The peer is the address, so `connect()` returns its socket
+impl ActiveStream for MockSocket {
+    type Address = MockPeer;
+    fn connect(peer: &MockPeer) -> io::Result<MockSocket> {
+        Ok(MockSocket(peer.0.clone()))
+    }
+    fn is_connected(&self) -> bool {
+        self.0.lock().unwrap().connected
+    }
+}
+
+impl SocketError for MockSocket {
+    fn take_socket_error(&self) -> io::Result<()> {
+        match self.0.lock().unwrap().socket_error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.0.lock().unwrap().write_shutdown = true;
+        Ok(())
+    }
+}
+
+/// A fault to inject into a `read()` or `write()` of the `FaultySocket`
+#[derive(Debug)]
+pub enum Fault {
+    /// Pass the call to the underlying socket, but limit buffer size to
+    /// this number of bytes (must be non-zero)
+    Short(usize),
+    /// Return `WouldBlock` without calling underlying socket
+    ///
+    /// This emulates delayed readiness. Note that the real event loop will
+    /// not deliver another event for the socket in this case, so you must
+    /// call `StreamDriver::ready()` again.
+    WouldBlock,
+    /// Return `ConnectionReset` error
+    Reset,
+    /// Return `BrokenPipe` error
+    BrokenPipe,
+    /// Return arbitrary error
+    Error(io::Error),
+}
+
+/// A socket wrapper which injects faults into reads and writes
+///
+/// Scripted faults are applied first, one per `read()` or `write()` call.
+/// When there are no scripted faults, and the socket is created with
+/// `FaultySocket::random`, reads and writes are split at random
+/// boundaries and spuriously return `WouldBlock`. Otherwise calls are
+/// passed to the underlying socket as is.
+#[derive(Debug)]
+pub struct FaultySocket<S> {
+    sock: S,
+    read_faults: VecDeque<Fault>,
+    write_faults: VecDeque<Fault>,
+    random: Option<Random>,
+}
+
+// A xorshift generator, we don't need a good one but we need it to be
+// reproducible
+#[derive(Debug)]
+struct Random {
+    state: u64,
+    max_chunk: usize,
+    would_block: u32,
+}
+
+impl Random {
+    fn next(&mut self) -> u32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state >> 32) as u32
+    }
+    fn fault(&mut self) -> Fault {
+        if self.next() % 100 < self.would_block {
+            Fault::WouldBlock
+        } else {
+            Fault::Short(1 + self.next() as usize % self.max_chunk)
+        }
+    }
+}
+
+impl<S> FaultySocket<S> {
+    /// Wrap a socket, only scripted faults are injected
+    pub fn new(sock: S) -> FaultySocket<S> {
+        FaultySocket {
+            sock: sock,
+            read_faults: VecDeque::new(),
+            write_faults: VecDeque::new(),
+            random: None,
+        }
+    }
+    /// Wrap a socket, injecting faults at random
+    ///
+    /// Every `read()` and `write()` returns `WouldBlock` with probability
+    /// of `would_block_percent` and transfers no more than `max_chunk`
+    /// bytes otherwise. Same `seed` produces the same sequence of faults.
+    pub fn random(sock: S, seed: u64, max_chunk: usize,
+        would_block_percent: u32)
+        -> FaultySocket<S>
+    {
+        assert!(max_chunk > 0);
+        assert!(would_block_percent < 100);
+        FaultySocket {
+            random: Some(Random {
+                // zero state would produce zeros forever
+                state: seed | 1,
+                max_chunk: max_chunk,
+                would_block: would_block_percent,
+            }),
+            .. FaultySocket::new(sock)
+        }
+    }
+    /// Add a fault for the next `read()` (after already scheduled ones)
+    pub fn push_read_fault(&mut self, fault: Fault) {
+        self.read_faults.push_back(fault);
+    }
+    /// Add a fault for the next `write()` (after already scheduled ones)
+    pub fn push_write_fault(&mut self, fault: Fault) {
+        self.write_faults.push_back(fault);
+    }
+    /// Returns a reference to the underlying socket
+    pub fn get_ref(&self) -> &S {
+        &self.sock
+    }
+    /// Returns a mutable reference to the underlying socket
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.sock
+    }
+    /// Unwraps the underlying socket
+    pub fn into_inner(self) -> S {
+        self.sock
+    }
+}
+
+fn next_fault(scripted: &mut VecDeque<Fault>, random: &mut Option<Random>)
+    -> Option<Fault>
+{
+    scripted.pop_front().or_else(|| random.as_mut().map(|r| r.fault()))
+}
+
+fn fault_error(fault: Fault) -> io::Error {
+    match fault {
+        Fault::Short(_) => unreachable!(),
+        Fault::WouldBlock => {
+            io::Error::new(io::ErrorKind::WouldBlock, "injected would block")
+        }
+        Fault::Reset => {
+            io::Error::new(io::ErrorKind::ConnectionReset, "injected reset")
+        }
+        Fault::BrokenPipe => {
+            io::Error::new(io::ErrorKind::BrokenPipe, "injected broken pipe")
+        }
+        Fault::Error(e) => e,
+    }
+}
+
+impl<S: Read> Read for FaultySocket<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match next_fault(&mut self.read_faults, &mut self.random) {
+            None => self.sock.read(buf),
+            Some(Fault::Short(num)) => {
+                let num = min(num, buf.len());
+                self.sock.read(&mut buf[..num])
+            }
+            Some(fault) => Err(fault_error(fault)),
+        }
+    }
+}
+
+impl<S: Write> Write for FaultySocket<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match next_fault(&mut self.write_faults, &mut self.random) {
+            None => self.sock.write(buf),
+            Some(Fault::Short(num)) => {
+                let num = min(num, buf.len());
+                self.sock.write(&buf[..num])
+            }
+            Some(fault) => Err(fault_error(fault)),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.sock.flush()
+    }
+}
+
+impl<S: Evented> Evented for FaultySocket<S> {
+    fn register(&self, selector: &mut Selector, token: Token,
+        interest: EventSet, opts: PollOpt)
+        -> io::Result<()>
+    {
+        self.sock.register(selector, token, interest, opts)
+    }
+    fn reregister(&self, selector: &mut Selector, token: Token,
+        interest: EventSet, opts: PollOpt)
+        -> io::Result<()>
+    {
+        self.sock.reregister(selector, token, interest, opts)
+    }
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.sock.deregister(selector)
+    }
+}
+
+impl<S: SocketError> SocketError for FaultySocket<S> {
+    fn take_socket_error(&self) -> io::Result<()> {
+        self.sock.take_socket_error()
+    }
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.sock.shutdown_write()
+    }
+}
+
+/// Drives a `Protocol` without an event loop
+///
+/// Every method emulates a single event of the state machine (i.e. an
+/// iteration of the event loop). The clock is virtual, it starts at zero and
+/// is changed only by `advance()`.
+///
+/// Socket is never registered in the loop, so any `StreamSocket` may be
+/// used, although `MockSocket` is the most useful one.
+pub struct StreamDriver<P: Protocol> {
+    stream: Option<Stream<P>>,
+    error: Option<Box<Error>>,
+    context: P::Context,
+    now: Time,
+    channel: Sender<_Notify>,
+    loop_api: MockLoop,
+}
+
+struct NoHandler;
+
+// We need a real (but never run) event loop to get a notification channel
+// and to support deprecated `Scope::timeout_ms`
+struct MockLoop {
+    event_loop: EventLoop<NoHandler>,
+    shutdown: bool,
+}
+
+impl Handler for NoHandler {
+    type Timeout = ();
+    type Message = _Notify;
+}
+
+impl _LoopApi for MockLoop {
+    fn register(&mut self, _io: &Evented, _token: Token,
+        _interest: EventSet, _opt: PollOpt) -> io::Result<()>
+    {
+        Ok(())
+    }
+    fn reregister(&mut self, _io: &Evented, _token: Token,
+        _interest: EventSet, _opt: PollOpt) -> io::Result<()>
+    {
+        Ok(())
+    }
+    fn deregister(&mut self, _io: &Evented) -> io::Result<()> {
+        Ok(())
+    }
+    fn timeout_ms(&mut self, _token: Token, delay: u64)
+        -> Result<Timeout, TimerError>
+    {
+        self.event_loop.timeout_ms((), delay)
+    }
+    fn clear_timeout(&mut self, timeout: Timeout) -> bool {
+        self.event_loop.clear_timeout(timeout)
+    }
+    fn shutdown(&mut self) {
+        self.shutdown = true;
+    }
+}
+
+/// Calls `f` with the scope of a mock loop
+///
+/// This is used to test state machines other than `Stream` by calling
+/// their methods directly.
+#[cfg(test)]
+pub fn with_scope<C, R, F>(context: &mut C, now: Time, f: F) -> R
+    where F: FnOnce(&mut Scope<C>) -> R
+{
+    let event_loop = EventLoop::new()
+        .expect("can't create event loop for the test scope");
+    let mut channel = event_loop.channel();
+    let mut loop_api = MockLoop {
+        event_loop: event_loop,
+        shutdown: false,
+    };
+    let mut scope = _scope(now, Token(0), context, &mut channel,
+                           &mut loop_api);
+    f(&mut scope)
+}
+
+impl<P: Protocol> StreamDriver<P> {
+    /// Create the protocol like the `Stream::new` does
+    ///
+    /// If `Protocol::create` fails, the driver is created in closed state
+    /// with the error stored.
+    pub fn new(sock: P::Socket, seed: P::Seed, context: P::Context)
+        -> StreamDriver<P>
+    {
+        StreamDriver::with_options(sock, seed, StreamOptions::new(), context)
+    }
+    /// Create the protocol like the `Stream::with_options` does
+    pub fn with_options(sock: P::Socket, seed: P::Seed,
+        options: StreamOptions, context: P::Context)
+        -> StreamDriver<P>
+    {
+        let event_loop = EventLoop::new()
+            .expect("can't create event loop for the test driver");
+        let mut driver = StreamDriver {
+            stream: None,
+            error: None,
+            context: context,
+            now: Time::zero(),
+            channel: event_loop.channel(),
+            loop_api: MockLoop {
+                event_loop: event_loop,
+                shutdown: false,
+            },
+        };
+        let res = {
+            let mut scope = _scope(driver.now, Token(0), &mut driver.context,
+                &mut driver.channel, &mut driver.loop_api);
+            stream::create(sock, seed, false, options, &mut scope)
+        };
+        match res {
+            Ok(stream) => driver.stream = Some(stream),
+            Err(e) => driver.error = Some(e),
+        }
+        driver
+    }
+    fn run<F>(&mut self, f: F)
+        where F: FnOnce(Stream<P>, &mut ::rotor::Scope<P::Context>)
+                  -> Result<Stream<P>, stream::Closed>
+    {
+        if let Some(stream) = self.stream.take() {
+            let mut scope = _scope(self.now, Token(0), &mut self.context,
+                &mut self.channel, &mut self.loop_api);
+            match f(stream, &mut scope) {
+                Ok(stream) => self.stream = Some(stream),
+                Err((e, _)) => self.error = e,
+            }
+        }
+    }
+    /// Emulate the readiness event of the socket
+    pub fn ready(&mut self, events: EventSet) {
+        self.run(|s, scope| stream::ready(s, events, scope))
+    }
+    /// Append data to the input buffer and emulate readable event
+    ///
+    /// This is useful if the socket is not a `MockSocket`, otherwise it's
+    /// similar to `MockPeer::push` followed by `ready()`
+    pub fn feed(&mut self, data: &[u8]) {
+        if let Some(ref mut stream) = self.stream {
+            stream.inbuf.extend(data);
+        }
+        self.ready(EventSet::readable())
+    }
+    /// Emulate `wakeup` (i.e. the `Notifier::wakeup()` call)
+    pub fn wakeup(&mut self) {
+        self.run(stream::wakeup)
+    }
+    /// Move virtual clock forward
+    ///
+    /// If the deadline or the idle timeout is reached, the `timeout`
+    /// handler is called. It's called at most once, even if the protocol
+    /// sets the deadline which is already reached (the real loop would call
+    /// it on next iteration), so use `advance(Duration::new(0, 0))` to fire
+    /// it again.
+    pub fn advance(&mut self, duration: Duration) {
+        self.now = self.now + duration;
+        let now = self.now;
+        let reached = match self.stream {
+            Some(ref s) => stream::timer(s).map(|x| x <= now)
+                .unwrap_or(false),
+            None => false,
+        };
+        if reached {
+            self.run(stream::timer_expired)
+        }
+    }
+    /// Current value of the virtual clock
+    pub fn now(&self) -> Time {
+        self.now
+    }
+    /// Returns true if the state machine is closed (with or without error)
+    pub fn is_closed(&self) -> bool {
+        self.stream.is_none()
+    }
+    /// Returns the error if the state machine is closed with error
+    pub fn error(&self) -> Option<&Error> {
+        self.error.as_ref().map(|x| &**x)
+    }
+    /// Returns true if the protocol called `Scope::shutdown_loop()`
+    pub fn is_shutdown(&self) -> bool {
+        self.loop_api.shutdown
+    }
+    /// Current expectation of the protocol
+    pub fn expectation(&self) -> Option<&Expectation> {
+        self.stream.as_ref().map(|x| &x.expectation)
+    }
+    /// Current deadline of the protocol
+    pub fn deadline(&self) -> Option<Time> {
+        self.stream.as_ref().and_then(|x| x.deadline)
+    }
+    /// Get the transport of the stream, to inspect or modify buffers
+    ///
+    /// Note: output buffer contains only the data which is not flushed to
+    /// the socket yet
+    pub fn transport(&mut self) -> Option<Transport<P::Socket>> {
+        self.stream.as_mut().map(|x| x.transport())
+    }
+    /// Get the protocol object
+    pub fn protocol(&mut self) -> Option<&mut P> {
+        self.stream.as_mut().map(|x| x.protocol())
+    }
+    /// Get the context passed to the protocol handlers
+    pub fn context(&mut self) -> &mut P::Context {
+        &mut self.context
+    }
+}
+
+const CHECK_DELIMITERS: [&'static [u8]; 3] = [b"\n", b"\r\n", b"aa"];
+
+#[derive(Debug, Clone, Copy)]
+enum Step {
+    Bytes(usize),
+    // min, index of the delimiter, max
+    Delimiter(usize, usize, usize),
+    Flush(usize),
+}
+
+struct Checker {
+    script: Rc<Vec<Step>>,
+    index: usize,
+    finishing: bool,
+}
+
+#[derive(Default)]
+struct CheckLog {
+    consumed: Vec<u8>,
+    rest: Vec<u8>,
+    done: bool,
+}
+
+impl Step {
+    fn expectation(self) -> Expectation {
+        match self {
+            Step::Bytes(n) => Expectation::Bytes(n),
+            Step::Delimiter(min, d, max) => {
+                Expectation::Delimiter(min, CHECK_DELIMITERS[d], max)
+            }
+            Step::Flush(n) => Expectation::Flush(n),
+        }
+    }
+}
+
+fn naive_find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|x| x == needle)
+}
+
+impl Checker {
+    fn step(&self) -> Step {
+        self.script[self.index]
+    }
+    fn next(mut self) -> Intent<Checker> {
+        self.index = (self.index + 1) % self.script.len();
+        let exp = self.step().expectation();
+        Intent::of(self).expect(exp)
+    }
+}
+
+impl Protocol for Checker {
+    type Context = CheckLog;
+    type Socket = FaultySocket<MockSocket>;
+    type Seed = Rc<Vec<Step>>;
+    fn create(script: Rc<Vec<Step>>, _sock: &mut Self::Socket,
+        _scope: &mut Scope<CheckLog>)
+        -> Intent<Self>
+    {
+        let exp = script[0].expectation();
+        Intent::of(Checker { script: script, index: 0, finishing: false })
+            .expect(exp)
+    }
+    fn bytes_read(self, transport: &mut Transport<Self::Socket>,
+        end: usize, scope: &mut Scope<CheckLog>)
+        -> Intent<Self>
+    {
+        let (inp, out) = transport.buffers();
+        let num = match self.step() {
+            Step::Bytes(n) => {
+                assert_eq!(end, n);
+                assert!(inp.len() >= n);
+                n
+            }
+            Step::Delimiter(min, d, _) => {
+                let delim = CHECK_DELIMITERS[d];
+                assert!(end >= min);
+                assert_eq!(&inp[end..end+delim.len()], delim);
+                // no earlier delimiter after the offset
+                assert!(naive_find(&inp[min..end+delim.len()-1], delim)
+                        .is_none());
+                end + delim.len()
+            }
+            Step::Flush(_) => panic!("bytes_read when flushing"),
+        };
+        scope.consumed.extend_from_slice(&inp[..num]);
+        out.extend(&inp[..num]);
+        inp.consume(num);
+        self.next()
+    }
+    fn bytes_flushed(self, transport: &mut Transport<Self::Socket>,
+        scope: &mut Scope<CheckLog>)
+        -> Intent<Self>
+    {
+        if self.finishing {
+            assert_eq!(transport.output().len(), 0);
+            scope.done = true;
+            return Intent::done();
+        }
+        match self.step() {
+            Step::Flush(n) => assert!(transport.output().len() <= n),
+            step => panic!("bytes_flushed when expecting {:?}", step),
+        }
+        self.next()
+    }
+    fn timeout(self, _transport: &mut Transport<Self::Socket>,
+        _scope: &mut Scope<CheckLog>)
+        -> Intent<Self>
+    {
+        panic!("unexpected timeout");
+    }
+    fn exception(mut self, transport: &mut Transport<Self::Socket>,
+        reason: Exception, scope: &mut Scope<CheckLog>)
+        -> Intent<Self>
+    {
+        let (inp, out) = transport.buffers();
+        match (reason, self.step()) {
+            (Exception::LimitReached, Step::Delimiter(min, d, max)) => {
+                assert!(!self.finishing);
+                assert!(inp.len() > max);
+                if inp.len() > min {
+                    assert!(naive_find(&inp[min..], CHECK_DELIMITERS[d])
+                            .is_none());
+                }
+                scope.consumed.extend_from_slice(&inp[..]);
+                out.extend(&inp[..]);
+                inp.consume(inp.len());
+                self.next()
+            }
+            (Exception::EndOfStream, _) if !self.finishing => {
+                scope.rest.extend_from_slice(&inp[..]);
+                self.finishing = true;
+                Intent::of(self).expect_flush()
+            }
+            (reason, step) => {
+                panic!("unexpected exception {:?} when expecting {:?}",
+                    reason, step);
+            }
+        }
+    }
+    fn fatal(self, reason: Exception, _scope: &mut Scope<CheckLog>)
+        -> Option<Box<Error>>
+    {
+        panic!("unexpected fatal error {:?}", reason);
+    }
+    fn wakeup(self, _transport: &mut Transport<Self::Socket>,
+        _scope: &mut Scope<CheckLog>)
+        -> Intent<Self>
+    {
+        panic!("unexpected wakeup");
+    }
+}
+
+/// Runs `Stream` with arbitrary expectations over arbitrary input
+///
+/// The data is decoded into chunking of the socket, a sequence of
+/// expectations and the input stream itself. The function panics if
+/// `Stream` breaks any guarantee of the expectations, for example if
+/// `bytes_read` is called with the offset not pointing to the delimiter,
+/// or if `LimitReached` is raised while there is a delimiter in the buffer.
+///
+/// This is the body of the fuzz target in `fuzz/`, but may be used with
+/// any other fuzzer or property-testing tool.
+pub fn check_expectations(data: &[u8]) {
+    let mut bytes = data.iter().cloned();
+    let mut byte = || bytes.next().unwrap_or(0) as usize;
+    let max_chunk = 1 + byte() % 16;
+    let would_block = (byte() % 50) as u32;
+    let seed = (byte() << 8 | byte()) as u64;
+    let num_steps = 1 + byte() % 8;
+    let mut script = Vec::with_capacity(num_steps + 1);
+    for _ in 0..num_steps {
+        let (kind, a, b) = (byte(), byte(), byte());
+        script.push(match kind % 3 {
+            0 => Step::Bytes(1 + a % 32),
+            1 => Step::Delimiter(a % 8, b % CHECK_DELIMITERS.len(),
+                                 a % 8 + b % 64),
+            _ => Step::Flush(a % 16),
+        });
+    }
+    // Script of just flushes would spin forever
+    if script.iter().all(|s| match *s { Step::Flush(_) => true, _ => false })
+    {
+        script.push(Step::Bytes(1));
+    }
+    let input = &data[min(data.len(), 5 + num_steps*3)..];
+
+    let (sock, peer) = pair();
+    peer.push(input);
+    peer.push_eof();
+    let sock = FaultySocket::random(sock, seed, max_chunk, would_block);
+    let mut driver = StreamDriver::<Checker>::new(sock, Rc::new(script),
+                                                  CheckLog::default());
+    for _ in 0..1000 + input.len()*20 {
+        if driver.is_closed() {
+            break;
+        }
+        driver.ready(EventSet::readable() | EventSet::writable());
+    }
+    assert!(driver.is_closed(), "stream is stuck");
+    assert!(driver.error().is_none());
+    let log = driver.context();
+    assert!(log.done);
+    assert_eq!(&peer.output()[..], &log.consumed[..]);
+    log.consumed.extend_from_slice(&log.rest);
+    assert_eq!(&log.consumed[..], input);
+}
+
+/// The protocol used in the tests of the crate
+#[cfg(test)]
+pub mod echo {
+    use std::error::Error;
+    use std::marker::PhantomData;
+    use std::time::Duration;
+
+    use rotor::Scope;
+    use {Protocol, Intent, Transport, Exception, StreamSocket};
+
+    /// Echoes lines back, closes connection after a second of inactivity
+    ///
+    /// Any exception or timeout closes the connection. Wakeup stops the
+    /// loop and sleeps keeping the deadline.
+    pub struct Echo<S>(PhantomData<S>);
+
+    fn echo<S>() -> Echo<S> {
+        Echo(PhantomData)
+    }
+
+    impl<S: StreamSocket> Protocol for Echo<S> {
+        type Context = ();
+        type Socket = S;
+        type Seed = ();
+        fn create(_seed: (), _sock: &mut S, scope: &mut Scope<()>)
+            -> Intent<Self>
+        {
+            Intent::of(echo()).expect_delimiter(b"\n", 100)
+                .deadline(scope.now() + Duration::new(1, 0))
+        }
+        fn bytes_read(self, transport: &mut Transport<S>,
+            end: usize, scope: &mut Scope<()>)
+            -> Intent<Self>
+        {
+            let (inp, out) = transport.buffers();
+            out.extend(&inp[..end+1]);
+            inp.consume(end+1);
+            Intent::of(echo()).expect_delimiter(b"\n", 100)
+                .deadline(scope.now() + Duration::new(1, 0))
+        }
+        fn bytes_flushed(self, _transport: &mut Transport<S>,
+            _scope: &mut Scope<()>)
+            -> Intent<Self>
+        {
+            unreachable!();
+        }
+        fn timeout(self, _transport: &mut Transport<S>,
+            _scope: &mut Scope<()>)
+            -> Intent<Self>
+        {
+            Intent::done()
+        }
+        fn exception(self, _transport: &mut Transport<S>,
+            _reason: Exception, _scope: &mut Scope<()>)
+            -> Intent<Self>
+        {
+            Intent::done()
+        }
+        fn fatal(self, _reason: Exception, _scope: &mut Scope<()>)
+            -> Option<Box<Error>>
+        {
+            None
+        }
+        fn wakeup(self, _transport: &mut Transport<S>,
+            scope: &mut Scope<()>)
+            -> Intent<Self>
+        {
+            scope.shutdown_loop();
+            Intent::of(echo()).sleep().keep_deadline()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write, ErrorKind};
+    use std::time::Duration;
+
+    use rotor::EventSet;
+    use super::{pair, WriteEvent, MockSocket, StreamDriver};
+    use super::{FaultySocket, Fault, Random, check_expectations};
+    use super::echo::Echo;
+    use {Expectation};
+
+    #[test]
+    fn read_boundaries() {
+        let (mut sock, peer) = pair();
+        peer.push(b"hello");
+        peer.push(b"world");
+        peer.push_eof();
+        let mut buf = [0u8; 3];
+        assert_eq!(sock.read(&mut buf).unwrap(), 3);
+        assert_eq!(sock.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"lo");
+        let mut buf = [0u8; 100];
+        assert_eq!(sock.read(&mut buf).unwrap(), 5);
+        assert_eq!(sock.read(&mut buf).unwrap(), 0);
+        assert_eq!(sock.read(&mut buf).unwrap(), 0);
+        assert_eq!(peer.read_calls(), 5);
+    }
+
+    #[test]
+    fn would_block() {
+        let (mut sock, peer) = pair();
+        let mut buf = [0u8; 10];
+        assert_eq!(sock.read(&mut buf).unwrap_err().kind(),
+                   ErrorKind::WouldBlock);
+        peer.push_would_block();
+        peer.push(b"x");
+        assert_eq!(sock.read(&mut buf).unwrap_err().kind(),
+                   ErrorKind::WouldBlock);
+        assert_eq!(sock.read(&mut buf).unwrap(), 1);
+    }
+
+    #[test]
+    fn short_write() {
+        let (mut sock, peer) = pair();
+        peer.push_write_event(WriteEvent::Accept(2));
+        peer.push_write_event(WriteEvent::WouldBlock);
+        assert_eq!(sock.write(b"hello").unwrap(), 2);
+        assert_eq!(sock.write(b"llo").unwrap_err().kind(),
+                   ErrorKind::WouldBlock);
+        assert_eq!(sock.write(b"llo").unwrap(), 3);
+        assert_eq!(peer.take_output(), b"hello");
+        assert_eq!(peer.output(), b"");
+    }
+
+    #[test]
+    fn driver_echo() {
+        let (sock, peer) = pair();
+        let mut driver = StreamDriver::<Echo<MockSocket>>::new(sock, (), ());
+        peer.push(b"hello\nwor");
+        driver.ready(EventSet::readable());
+        assert_eq!(peer.take_output(), b"hello\n");
+        driver.feed(b"ld\n");
+        assert_eq!(peer.take_output(), b"world\n");
+        peer.block_writes(true);
+        driver.feed(b"again\n");
+        assert_eq!(&driver.transport().unwrap().output()[..], b"again\n");
+        match driver.expectation() {
+            Some(&Expectation::Delimiter(0, b"\n", 100)) => {}
+            x => panic!("Wrong expectation {:?}", x),
+        }
+    }
+
+    #[test]
+    fn driver_timeout() {
+        let (sock, _peer) = pair();
+        let mut driver = StreamDriver::<Echo<MockSocket>>::new(sock, (), ());
+        driver.feed(b"x\n");
+        driver.advance(Duration::from_millis(500));
+        assert!(driver.deadline().is_some());
+        driver.feed(b"y\n");
+        driver.advance(Duration::from_millis(700));
+        assert!(!driver.is_closed());
+        driver.advance(Duration::from_millis(300));
+        assert!(driver.is_closed());
+        assert!(driver.error().is_none());
+    }
+
+    #[test]
+    fn driver_wakeup() {
+        let (sock, _peer) = pair();
+        let mut driver = StreamDriver::<Echo<MockSocket>>::new(sock, (), ());
+        driver.wakeup();
+        assert!(driver.is_shutdown());
+        match driver.expectation() {
+            Some(&Expectation::Sleep) => {}
+            x => panic!("Wrong expectation {:?}", x),
+        }
+    }
+
+    #[test]
+    fn faulty_scripted() {
+        let (sock, peer) = pair();
+        let mut sock = FaultySocket::new(sock);
+        peer.push(b"hello");
+        sock.push_read_fault(Fault::Short(2));
+        sock.push_read_fault(Fault::WouldBlock);
+        sock.push_read_fault(Fault::Reset);
+        sock.push_write_fault(Fault::BrokenPipe);
+        let mut buf = [0u8; 10];
+        assert_eq!(sock.read(&mut buf).unwrap(), 2);
+        assert_eq!(sock.read(&mut buf).unwrap_err().kind(),
+                   ErrorKind::WouldBlock);
+        assert_eq!(sock.read(&mut buf).unwrap_err().kind(),
+                   ErrorKind::ConnectionReset);
+        assert_eq!(sock.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"llo");
+        assert_eq!(sock.write(b"x").unwrap_err().kind(),
+                   ErrorKind::BrokenPipe);
+        assert_eq!(sock.write(b"x").unwrap(), 1);
+        assert_eq!(peer.read_calls(), 2);
+    }
+
+    #[test]
+    fn faulty_echo() {
+        let input = b"first line\nsecond\n\nthe last line of input\n";
+        for seed in 0..100 {
+            let (sock, peer) = pair();
+            let sock = FaultySocket::random(sock, seed, 5, 30);
+            let mut driver = StreamDriver::<Echo<_>>::new(sock, (), ());
+            peer.push(input);
+            for _ in 0..200 {
+                driver.ready(EventSet::readable() | EventSet::writable());
+            }
+            assert!(!driver.is_closed());
+            assert_eq!(&peer.output()[..], &input[..]);
+        }
+    }
+
+    #[test]
+    fn expectations_delimiter_offset() {
+        // Delimiter(3, "\n", 51) with "\n" both before and after offset
+        check_expectations(b"\x00\x00\x00\x00\x00\x01\x03\x30\
+                             a\nbcd\nef\n");
+    }
+
+    #[test]
+    fn expectations_random() {
+        let mut rnd = Random { state: 1, max_chunk: 1, would_block: 0 };
+        for _ in 0..200 {
+            let len = rnd.next() as usize % 200;
+            let data = (0..len).map(|i| {
+                let x = rnd.next() as u8;
+                // header is arbitrary, input is mostly delimiters
+                if i < 28 { x } else { b"ab\r\n"[x as usize % 4] }
+            }).collect::<Vec<_>>();
+            check_expectations(&data);
+        }
+    }
+}
@@ -1,6 +1,8 @@
 use std::io;
 use std::any::Any;
-use std::net::SocketAddr;
+use std::net::{SocketAddr, Shutdown};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
 #[cfg(unix)]
 use std::path::PathBuf;
 
@@ -20,6 +22,10 @@ impl ActiveStream for tcp::TcpStream {
     fn connect(addr: &SocketAddr) -> io::Result<Self> {
         tcp::TcpStream::connect(addr)
     }
+    fn is_connected(&self) -> bool {
+        // The peer address is unknown until the handshake is complete
+        self.peer_addr().is_ok()
+    }
 }
 
 #[cfg(unix)]
@@ -34,6 +40,13 @@ impl SocketError for tcp::TcpStream {
     fn take_socket_error(&self) -> io::Result<()> {
         tcp::TcpStream::take_socket_error(self)
     }
+    fn shutdown_write(&self) -> io::Result<()> {
+        tcp::TcpStream::shutdown(self, Shutdown::Write)
+    }
+    #[cfg(unix)]
+    fn zero_copy_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
 }
 
 #[cfg(unix)]
@@ -41,4 +54,16 @@ impl SocketError for unix::UnixStream {
     fn take_socket_error(&self) -> io::Result<()> {
         Ok(())
     }
+    fn shutdown_write(&self) -> io::Result<()> {
+        use libc;
+
+        // mio 0.5 has no `shutdown()` for unix sockets
+        match unsafe { libc::shutdown(self.as_raw_fd(), libc::SHUT_WR) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+    fn zero_copy_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
 }
@@ -1,4 +1,9 @@
-use {Buf, Transport, StreamSocket};
+use std::fs::File;
+use std::sync::Arc;
+
+use rotor::Time;
+
+use {Buf, Transport, StreamSocket, ProxyHeader, Stats};
 
 
 impl<'a, S: StreamSocket> Transport<'a, S> {
@@ -23,6 +28,9 @@ impl<'a, S: StreamSocket> Transport<'a, S> {
     ///
     /// It's expected that you only inspect and write to the output buffer
     /// but never `consume()`
+    ///
+    /// Note: after `send_file()` the buffer only contains the data written
+    /// after the file.
     pub fn output<'x>(&'x mut self) -> &'x mut Buf {
         self.outbuf
     }
@@ -34,4 +42,141 @@ impl<'a, S: StreamSocket> Transport<'a, S> {
     pub fn buffers<'x>(&'x mut self) -> (&'x mut Buf, &'x mut Buf) {
         (self.inbuf, self.outbuf)
     }
+    /// Returns addresses received in the PROXY protocol header
+    ///
+    /// This is only set for connections accepted with `ProxyProtocolStream`.
+    /// And even in this case it's `None` if the proxy has not passed the
+    /// addresses (e.g. connections made by proxy itself for health checks).
+    pub fn proxy_header(&self) -> Option<&ProxyHeader> {
+        self.proxy
+    }
+    /// Returns I/O statistics of the connection
+    pub fn stats(&self) -> &Stats {
+        self.stats
+    }
+    /// Returns true if the server which accepted the connection is draining
+    ///
+    /// See `Drain` for more info. Protocols may use this to close the
+    /// connection after the current request.
+    pub fn is_draining(&self) -> bool {
+        self.options.drain.as_ref().map(|d| d.is_draining())
+            .unwrap_or(false)
+    }
+    /// Returns the deadline of the current intent
+    ///
+    /// In the callbacks of the protocol this is the deadline which is kept
+    /// by `Intent::keep_deadline()`.
+    pub fn deadline(&self) -> Option<Time> {
+        self.deadline
+    }
+    /// Returns true if output buffer reached the high-water mark
+    ///
+    /// Protocols producing a lot of data (or external threads that write
+    /// to the buffer) should stop writing when this returns true, and
+    /// continue when buffer is flushed (usually by waiting for
+    /// `Expectation::Flush(n)`). The mark is set by
+    /// `StreamOptions::output_high_water`.
+    pub fn output_is_full(&self) -> bool {
+        self.outbuf.len() + self.outqueue.buffered() >=
+            self.options.output_high_water
+    }
+    /// Send `len` bytes of the `file` starting at `offset`
+    ///
+    /// The file is sent after the data already written to the output
+    /// buffer, and before anything written to the buffer afterwards. On
+    /// linux it's sent by `sendfile` system call if socket supports it (see
+    /// `SocketError::zero_copy_fd`, both TCP and Unix sockets do), otherwise
+    /// or if the file doesn't support `sendfile`, it's copied by small
+    /// chunks.
+    ///
+    /// The bytes of the file are accounted by `Expectation::Flush`, but
+    /// not by `output_is_full()` as they are not kept in memory. If file
+    /// is shorter than `offset + len` the stream is closed with
+    /// `Exception::WriteError`.
+    pub fn send_file(&mut self, file: File, offset: u64, len: u64) {
+        self.outqueue.push_file(self.outbuf, file, offset, len);
+    }
+    /// Send the shared data without copying it to the output buffer
+    ///
+    /// This is useful to send the same data to many connections (cached
+    /// responses, broadcast messages): the data is allocated once, however
+    /// many connections it is sent to. Like with `send_file()` the data is
+    /// sent after the data already in the output buffer and before anything
+    /// written afterwards. Adjacent chunks are sent with a single `writev`
+    /// system call if socket supports it (see `SocketError::zero_copy_fd`).
+    ///
+    /// The data is accounted by `Expectation::Flush`, `output_is_full()` and
+    /// `StreamOptions::max_output_buffer` until it's written.
+    pub fn send_shared(&mut self, data: Arc<Vec<u8>>) {
+        self.outqueue.push_shared(self.outbuf, data);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::process;
+    use std::sync::Arc;
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    use rotor::EventSet;
+    use testing::{pair, MockSocket, StreamDriver, WriteEvent};
+    use testing::echo::Echo;
+
+    #[test]
+    fn send_file() {
+        let path = env::temp_dir().join(
+            format!("rotor-stream-send-file-{}", process::id()));
+        File::create(&path).unwrap().write_all(b"0123456789").unwrap();
+        let (sock, peer) = pair();
+        let mut driver = StreamDriver::<Echo<MockSocket>>::new(sock, (), ());
+        {
+            let mut transport = driver.transport().unwrap();
+            transport.output().extend(b"head ");
+            transport.send_file(File::open(&path).unwrap(), 2, 5);
+            transport.output().extend(b" tail");
+            assert_eq!(&transport.output()[..], b" tail");
+        }
+        peer.push_write_event(WriteEvent::Accept(3));
+        peer.push_write_event(WriteEvent::Accept(100));
+        peer.push_write_event(WriteEvent::Accept(2));
+        peer.push_write_event(WriteEvent::WouldBlock);
+        driver.wakeup();
+        assert_eq!(peer.take_output(), b"head 23");
+        driver.ready(EventSet::writable());
+        assert_eq!(peer.take_output(), b"456 tail");
+        assert!(!driver.is_closed());
+
+        // File is shorter than requested
+        driver.transport().unwrap()
+            .send_file(File::open(&path).unwrap(), 8, 5);
+        driver.ready(EventSet::writable());
+        assert_eq!(peer.take_output(), b"89");
+        assert!(driver.is_closed());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn send_shared() {
+        let data = Arc::new(b"shared".to_vec());
+        let (sock, peer) = pair();
+        let mut driver = StreamDriver::<Echo<MockSocket>>::new(sock, (), ());
+        {
+            let mut transport = driver.transport().unwrap();
+            transport.output().extend(b"<");
+            transport.send_shared(data.clone());
+            transport.send_shared(data.clone());
+            transport.output().extend(b">");
+        }
+        assert_eq!(Arc::strong_count(&data), 3);
+        peer.push_write_event(WriteEvent::Accept(100));
+        peer.push_write_event(WriteEvent::Accept(4));
+        peer.push_write_event(WriteEvent::WouldBlock);
+        driver.wakeup();
+        assert_eq!(peer.take_output(), b"<shar");
+        driver.ready(EventSet::writable());
+        assert_eq!(peer.take_output(), b"edshared>");
+        assert_eq!(Arc::strong_count(&data), 1);
+    }
 }
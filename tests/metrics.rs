@@ -0,0 +1,34 @@
+//! The metrics receiver is global, so the test lives in it's own binary
+//! to not interfere with other tests
+extern crate rotor_stream;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rotor_stream::{Metrics, Stats, set_metrics};
+use rotor_stream::testing::check_expectations;
+
+static BYTES_READ: AtomicUsize = AtomicUsize::new(0);
+static CLOSED: AtomicUsize = AtomicUsize::new(0);
+
+struct Counters;
+
+impl Metrics for Counters {
+    fn bytes_read(&self, bytes: usize) {
+        BYTES_READ.fetch_add(bytes, Ordering::SeqCst);
+    }
+    fn connection_closed(&self, _stats: &Stats) {
+        CLOSED.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+struct Noop;
+impl Metrics for Noop {}
+
+#[test]
+fn counters() {
+    assert!(set_metrics(Box::new(Counters)).is_ok());
+    assert!(set_metrics(Box::new(Noop)).is_err());
+    check_expectations(b"\x00\x00\x00\x00\x00\x00\x01\x00hello");
+    assert_eq!(BYTES_READ.load(Ordering::SeqCst), 5);
+    assert_eq!(CLOSED.load(Ordering::SeqCst), 1);
+}